@@ -10,6 +10,32 @@ use aes_gcm_siv::{
 use base64::{engine::general_purpose, DecodeError, Engine as _};
 use bytes::{BufMut, Bytes, BytesMut};
 use general_purpose::STANDARD_NO_PAD as BASE64;
+use std::fmt;
+
+/// The length of a key once decoded
+const KEY_LEN: usize = 32;
+
+/// Why a key can't be used
+#[derive(Debug)]
+pub enum KeyError {
+    /// The key isn't base64
+    Decode(DecodeError),
+    /// The key decodes to this many bytes instead of 32
+    Length(usize),
+}
+impl fmt::Display for KeyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeyError::Decode(e) => write!(f, "{}", e),
+            KeyError::Length(len) => write!(f, "the key is {} bytes long instead of {}", len, KEY_LEN),
+        }
+    }
+}
+impl From<DecodeError> for KeyError {
+    fn from(e: DecodeError) -> Self {
+        KeyError::Decode(e)
+    }
+}
 
 #[derive(Clone)]
 pub struct AES {
@@ -19,9 +45,14 @@ pub struct AES {
 
 impl AES {
     /// This function will create a new AES instance with a random key if no key is provided.
-    pub fn new(key: Option<&str>) -> Result<Self, DecodeError> {
+    /// # Errors
+    /// * The key isn't base64 or isn't 32 bytes long
+    pub fn new(key: Option<&str>) -> Result<Self, KeyError> {
         if key.is_some() {
             let key_vec = BASE64.decode(key.unwrap())?;
+            if key_vec.len() != KEY_LEN {
+                return Err(KeyError::Length(key_vec.len()));
+            }
             return Ok(AES {
                 key: key.unwrap().to_string(),
                 cipher: Aes256GcmSiv::new(GenericArray::from_slice(key_vec.as_slice())),
@@ -78,7 +109,7 @@ impl AES {
     }
 
     pub fn decrypt_b64(&self, b64_cipher: String) -> Result<Bytes, Error> {
-        let b64_decode = Bytes::from(BASE64.decode(b64_cipher).unwrap());
+        let b64_decode = Bytes::from(BASE64.decode(b64_cipher).map_err(|_| Error)?);
 
         self.decrypt(b64_decode)
     }
@@ -1,123 +1,121 @@
 // SPDX-FileCopyrightText: Copyright 2023 tSVoI
 // SPDX-License-Identifier: GPL-3.0-only
 
-use bytes::{Bytes, BytesMut};
+use bytes::Bytes;
 use flume::{Receiver, Sender};
 use std::sync::{atomic::AtomicI8, Arc, Mutex};
 
-use crate::audio::Audio;
-use crate::audio::DeviceKind;
-use miniaudio::{Device, DeviceConfig, DeviceType, Format, ShareMode};
+use crate::audio::device::{DeviceSelection, ManagedDevice};
+use crate::audio::{DeviceError, DeviceKind};
 use opus::{Application, Bitrate, Channels, Encoder};
 
 pub struct AudioCapture {
-    capture_device: Device,
-    capture_tx: Sender<Bytes>,
+    capture_device: ManagedDevice,
     capture_rx: Receiver<Bytes>,
-    intensity_tx: Sender<i8>,
     intensity_rx: Receiver<i8>,
     threshold: Arc<AtomicI8>,
     encoder: Arc<Mutex<Encoder>>,
 }
 impl AudioCapture {
-    /// Creates a DeviceConfig for a capture device
-    /// # Arguments
-    /// * `device_name` - The name of the device to use
-    /// * `channels` - The number of channels to use
-    /// * `sample_rate` - The sample rate to use
-    pub fn create_config(device_name: String, channels: u32, sample_rate: u32) -> DeviceConfig {
-        let device_id = Audio::get_device_id(&device_name, DeviceKind::Capture);
-        let mut config = DeviceConfig::new(DeviceType::Capture);
-        config.capture_mut().set_format(Format::S16);
-        config.capture_mut().set_channels(channels);
-        config.capture_mut().set_share_mode(ShareMode::Shared);
-        config.capture_mut().set_device_id(device_id);
-        config.set_sample_rate(sample_rate);
-        //config.set_period_size_in_milliseconds(10);
-        config
-    }
-
     /// Creates a new AudioCapture instance
     /// # Arguments
-    /// * `device_id` - The DeviceId of the device to use
-    /// * `channels` - The number of channels to use
-    /// * `sample_rate` - The sample rate to use
+    /// * `selection` - The device, channels and sample rate to use
     /// * `encoder_bitrate` - The bitrate to use for the encoder
     /// * `active_threshold` - The RMS threshold to record and encode the sample
-    pub fn new(device_config: DeviceConfig, encoder_bitrate: i32, active_threshold: i8) -> Self {
+    pub fn new(
+        selection: DeviceSelection,
+        encoder_bitrate: i32,
+        active_threshold: i8,
+    ) -> Result<Self, DeviceError> {
         let (capture_tx, capture_rx) = flume::unbounded();
         let (intensity_tx, intensity_rx) = flume::unbounded();
-        let capture_tx_clone = capture_tx.clone();
-        let intensity_tx_clone = intensity_tx.clone();
 
         let threshold = Arc::new(AtomicI8::new(active_threshold));
         let threshold_clone = threshold.clone();
 
-        let encoder_channels = match device_config.capture().channels() {
-            1 => Channels::Mono,
-            2 => Channels::Stereo,
-            _ => panic!("Invalid channel count"),
-        };
-        let encoder = Arc::new(Mutex::new(
-            Encoder::new(
-                device_config.sample_rate(),
-                encoder_channels,
-                Application::Voip,
-            )
-            .unwrap(),
-        ));
-        encoder
-            .lock()
-            .unwrap()
-            .set_bitrate(Bitrate::Bits(encoder_bitrate))
-            .unwrap();
-        encoder.lock().unwrap().set_vbr(true).unwrap();
+        let encoder = Arc::new(Mutex::new(Self::create_encoder(
+            &selection,
+            Bitrate::Bits(encoder_bitrate),
+        )?));
         let encoder_clone = encoder.clone();
 
-        let mut capture_device: Device = Device::new(None, &device_config).unwrap();
-        capture_device.set_data_callback(move |_, _, input| {
-            let input_samples = input.as_samples::<i16>();
-            let num_samples = input_samples.len();
-
-            //Calculate the sample RMS
-            let sum: f32 = input_samples
-                .iter()
-                .map(|&s| (s as f32 / i16::MAX as f32).powi(2))
-                .sum();
-            let rms = (((sum / num_samples as f32).sqrt() + 0.0002) * 100.0) as i8;
-            intensity_tx_clone.send(rms).unwrap();
-
-            //If the RMS is above the threshold, encode and push to the queue
-            if rms > threshold_clone.load(std::sync::atomic::Ordering::Relaxed) {
-                let encoded = Bytes::from(
-                    encoder_clone
-                        .lock()
-                        .unwrap()
-                        .encode_vec(input_samples, 512)
-                        .unwrap(),
+        let capture_device = ManagedDevice::new(
+            DeviceKind::Capture,
+            selection,
+            Box::new(move |_, _, input| {
+                let input_samples = input.as_samples::<i16>();
+                Self::process(
+                    input_samples,
+                    &threshold_clone,
+                    &encoder_clone,
+                    &capture_tx,
+                    &intensity_tx,
                 );
-                capture_tx_clone.send(encoded).unwrap();
-            }
-        });
-        AudioCapture {
+            }),
+        )?;
+        Ok(AudioCapture {
             capture_device,
-            capture_tx,
             capture_rx,
-            intensity_tx,
             intensity_rx,
             threshold,
             encoder,
+        })
+    }
+
+    /// Creates an opus encoder matching the capture format
+    fn create_encoder(selection: &DeviceSelection, bitrate: Bitrate) -> Result<Encoder, DeviceError> {
+        let codec_error = |e: String| DeviceError::Codec(selection.device.clone(), DeviceKind::Capture, e);
+        let encoder_channels = match selection.channels {
+            1 => Channels::Mono,
+            2 => Channels::Stereo,
+            n => return Err(codec_error(format!("invalid channel count {}", n))),
+        };
+        let mut encoder = Encoder::new(selection.sample_rate, encoder_channels, Application::Voip)
+            .map_err(|e| codec_error(e.to_string()))?;
+        encoder.set_bitrate(bitrate).map_err(|e| codec_error(e.to_string()))?;
+        encoder.set_vbr(true).map_err(|e| codec_error(e.to_string()))?;
+        Ok(encoder)
+    }
+
+    /// Measures the sample RMS and encodes it if it's above the threshold
+    fn process(
+        input_samples: &[i16],
+        threshold: &AtomicI8,
+        encoder: &Mutex<Encoder>,
+        capture_tx: &Sender<Bytes>,
+        intensity_tx: &Sender<i8>,
+    ) {
+        let num_samples = input_samples.len();
+
+        //Calculate the sample RMS
+        let sum: f32 = input_samples
+            .iter()
+            .map(|&s| (s as f32 / i16::MAX as f32).powi(2))
+            .sum();
+        let rms = (((sum / num_samples as f32).sqrt() + 0.0002) * 100.0) as i8;
+        intensity_tx.send(rms).unwrap();
+
+        //If the RMS is above the threshold, encode and push to the queue
+        if rms > threshold.load(std::sync::atomic::Ordering::Relaxed) {
+            let encoded = Bytes::from(
+                encoder
+                    .lock()
+                    .unwrap()
+                    .encode_vec(input_samples, 512)
+                    .unwrap(),
+            );
+            capture_tx.send(encoded).unwrap();
         }
     }
 
     /// Starts the capture device
-    pub fn start(&self) {
-        self.capture_device.start().unwrap();
+    pub fn start(&self) -> Result<(), DeviceError> {
+        self.capture_device.start()
     }
 
     /// Stops the capture device
-    pub fn stop(&self) {
-        self.capture_device.stop().unwrap();
+    pub fn stop(&self) -> Result<(), DeviceError> {
+        self.capture_device.stop()
     }
 
     /// Returns the capture receiver
@@ -145,38 +143,40 @@ impl AudioCapture {
             .unwrap();
     }
 
-    pub fn change_device(&mut self, device_name: String, channels: u32, sample_rate: u32) {
-        self.stop();
-        let config = Self::create_config(device_name, channels, sample_rate);
-
-        let intensity_tx = self.intensity_tx.clone();
-        let threshold = self.threshold.clone();
-        let encoder_clone = self.encoder.clone();
-        let capture_tx = self.capture_tx.clone();
-
-        let mut capture_device = Device::new(None, &config).unwrap();
-        capture_device.set_data_callback(move |_, _, input| {
-            let input_samples = input.as_samples::<i16>();
-            let num_samples = input_samples.len();
-
-            //Calculate the sample RMS
-            let sum: f32 = input_samples
-                .iter()
-                .map(|&s| (s as f32 / i16::MAX as f32).powi(2))
-                .sum();
-            let rms = (((sum / num_samples as f32).sqrt() + 0.0002) * 100.0) as i8;
-            intensity_tx.send(rms).unwrap();
-
-            //If the RMS is above the threshold, encode and push to the queue
-            if rms > threshold.load(std::sync::atomic::Ordering::Relaxed) {
-                let mut encoded = BytesMut::with_capacity(1024);
-                let _ = encoder_clone
-                    .lock()
-                    .unwrap()
-                    .encode(input_samples, encoded.as_mut())
-                    .unwrap();
-                capture_tx.send(encoded.freeze()).unwrap();
-            }
-        });
+    /// Returns the device, channels and sample rate in use
+    pub fn get_selection(&self) -> &DeviceSelection {
+        self.capture_device.get_selection()
+    }
+
+    /// Switches to another capture device, the encoder is kept unless the format changes.
+    /// On failure the previous device keeps capturing.
+    /// # Arguments
+    /// * `selection` - The device, channels and sample rate to switch to
+    pub fn change_device(&mut self, selection: DeviceSelection) -> Result<(), DeviceError> {
+        if selection.same_format(self.get_selection()) {
+            return self.capture_device.switch(selection);
+        }
+
+        let bitrate = self
+            .encoder
+            .lock()
+            .unwrap()
+            .get_bitrate()
+            .unwrap_or(Bitrate::Auto);
+        let encoder = Self::create_encoder(&selection, bitrate)?;
+        //Swap the encoder while no device can run the callback
+        let was_started = self.capture_device.is_started();
+        if was_started {
+            self.capture_device.stop()?;
+        }
+        let previous = std::mem::replace(&mut *self.encoder.lock().unwrap(), encoder);
+        let switched = self.capture_device.switch(selection);
+        if switched.is_err() {
+            *self.encoder.lock().unwrap() = previous;
+        }
+        if was_started {
+            self.capture_device.start()?;
+        }
+        switched
     }
 }
@@ -0,0 +1,153 @@
+// SPDX-FileCopyrightText: Copyright 2023 tSVoI
+// SPDX-License-Identifier: GPL-3.0-only
+
+use std::sync::{Arc, Mutex};
+
+use miniaudio::{Device, DeviceConfig, DeviceType, Format, Frames, FramesMut, RawDevice, ShareMode};
+
+use crate::audio::{Audio, DeviceError, DeviceKind};
+
+/// The data callback shared by every device a ManagedDevice builds
+pub type DataCallback = dyn FnMut(&RawDevice, &mut FramesMut, &Frames) + Send;
+
+/// The device a user picked and the format it should be opened with
+#[derive(Clone, Debug, PartialEq)]
+pub struct DeviceSelection {
+    /// The serialized id, name or index of the device, `_` for the default device
+    pub device: String,
+    pub channels: u32,
+    pub sample_rate: u32,
+}
+impl DeviceSelection {
+    pub fn new(device: &str, channels: u32, sample_rate: u32) -> Self {
+        DeviceSelection {
+            device: device.to_string(),
+            channels,
+            sample_rate,
+        }
+    }
+
+    /// Returns true if both selections need the same encoder/decoder
+    pub fn same_format(&self, other: &DeviceSelection) -> bool {
+        self.channels == other.channels && self.sample_rate == other.sample_rate
+    }
+}
+
+/// Owns a miniaudio device and rebuilds it when the user switches devices.
+/// The data callback outlives the devices so any encoder/decoder state it holds is kept.
+pub struct ManagedDevice {
+    kind: DeviceKind,
+    device: Device,
+    selection: DeviceSelection,
+    callback: Arc<Mutex<Box<DataCallback>>>,
+}
+impl ManagedDevice {
+    /// Creates a new ManagedDevice, the device is not started
+    /// # Arguments
+    /// * `kind` - Whether it's a capture or a playback device
+    /// * `selection` - The device to open
+    /// * `callback` - The data callback
+    pub fn new(
+        kind: DeviceKind,
+        selection: DeviceSelection,
+        callback: Box<DataCallback>,
+    ) -> Result<Self, DeviceError> {
+        let callback = Arc::new(Mutex::new(callback));
+        let device = Self::build(kind, &selection, callback.clone())?;
+        Ok(ManagedDevice {
+            kind,
+            device,
+            selection,
+            callback,
+        })
+    }
+
+    /// Creates a DeviceConfig for the selected device
+    /// # Arguments
+    /// * `kind` - Whether it's a capture or a playback device
+    /// * `selection` - The device to open
+    pub fn create_config(
+        kind: DeviceKind,
+        selection: &DeviceSelection,
+    ) -> Result<DeviceConfig, DeviceError> {
+        let device_id = Audio::get_device_id(&selection.device, kind)?;
+        let mut config = match kind {
+            DeviceKind::Capture => {
+                let mut config = DeviceConfig::new(DeviceType::Capture);
+                config.capture_mut().set_format(Format::S16);
+                config.capture_mut().set_channels(selection.channels);
+                config.capture_mut().set_share_mode(ShareMode::Shared);
+                config.capture_mut().set_device_id(device_id);
+                config
+            }
+            DeviceKind::Playback => {
+                let mut config = DeviceConfig::new(DeviceType::Playback);
+                config.playback_mut().set_format(Format::S16);
+                config.playback_mut().set_channels(selection.channels);
+                config.playback_mut().set_share_mode(ShareMode::Shared);
+                config.playback_mut().set_device_id(device_id);
+                config
+            }
+        };
+        config.set_sample_rate(selection.sample_rate);
+        //config.set_period_size_in_milliseconds(10);
+        Ok(config)
+    }
+
+    fn build(
+        kind: DeviceKind,
+        selection: &DeviceSelection,
+        callback: Arc<Mutex<Box<DataCallback>>>,
+    ) -> Result<Device, DeviceError> {
+        let config = Self::create_config(kind, selection)?;
+        let mut device = Device::new(Some(Audio::context()), &config)
+            .map_err(|e| DeviceError::Backend(selection.device.clone(), kind, e))?;
+        device.set_data_callback(move |raw, output, input| {
+            (callback.lock().unwrap())(raw, output, input);
+        });
+        Ok(device)
+    }
+
+    /// Starts the device
+    pub fn start(&self) -> Result<(), DeviceError> {
+        self.device
+            .start()
+            .map_err(|e| DeviceError::Backend(self.selection.device.clone(), self.kind, e))
+    }
+
+    /// Stops the device
+    pub fn stop(&self) -> Result<(), DeviceError> {
+        self.device
+            .stop()
+            .map_err(|e| DeviceError::Backend(self.selection.device.clone(), self.kind, e))
+    }
+
+    /// Replaces the device. The new device is built before the old one is stopped,
+    /// so on failure the old device keeps running.
+    /// # Arguments
+    /// * `selection` - The device to switch to
+    pub fn switch(&mut self, selection: DeviceSelection) -> Result<(), DeviceError> {
+        let device = Self::build(self.kind, &selection, self.callback.clone())?;
+        let was_started = self.device.is_started();
+        if was_started {
+            let _ = self.device.stop();
+            if let Err(e) = device.start() {
+                let _ = self.device.start();
+                return Err(DeviceError::Backend(selection.device, self.kind, e));
+            }
+        }
+        self.device = device;
+        self.selection = selection;
+        Ok(())
+    }
+
+    /// Returns true if the device is running
+    pub fn is_started(&self) -> bool {
+        self.device.is_started()
+    }
+
+    /// Returns the current selection
+    pub fn get_selection(&self) -> &DeviceSelection {
+        &self.selection
+    }
+}
@@ -1,21 +1,175 @@
 // SPDX-FileCopyrightText: Copyright 2023 tSVoI
 // SPDX-License-Identifier: GPL-3.0-only
 
-use miniaudio::{Backend, Context, DeviceId};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD as BASE64, Engine as _};
+use miniaudio::{Backend, Context, DeviceId, DeviceInfo, DeviceType, Error, Format, ShareMode};
+use serde_json::{json, Value};
+use std::sync::Mutex;
+
+use crate::audio::device::DeviceSelection;
 
 pub mod capture;
+pub mod device;
 pub mod playback;
+pub mod watcher;
 
-#[derive(PartialEq)]
+#[derive(PartialEq, Eq, Hash, Clone, Copy, Debug)]
 pub enum DeviceKind {
     Capture,
     Playback,
 }
+impl DeviceKind {
+    pub fn to_text(self) -> &'static str {
+        match self {
+            DeviceKind::Capture => "capture",
+            DeviceKind::Playback => "playback",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum DeviceError {
+    /// No device matched the selector
+    NotFound(String, DeviceKind),
+    /// More than one device has the selector as its name
+    Ambiguous(String, DeviceKind),
+    /// The backend failed to create, start or stop the device
+    Backend(String, DeviceKind, Error),
+    /// The channel count or sample rate can't be used by opus
+    Codec(String, DeviceKind, String),
+}
+impl std::fmt::Display for DeviceError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DeviceError::NotFound(device, kind) => {
+                write!(f, "{} device {} not found", kind.to_text(), device)
+            }
+            DeviceError::Ambiguous(device, kind) => {
+                write!(f, "more than one {} device is named {}, use its id", kind.to_text(), device)
+            }
+            DeviceError::Backend(device, kind, e) => {
+                write!(f, "{} device {} failed: {}", kind.to_text(), device, e)
+            }
+            DeviceError::Codec(device, kind, e) => {
+                write!(f, "{} device {} has an unsupported format: {}", kind.to_text(), device, e)
+            }
+        }
+    }
+}
+/// Sample rates reported to front-ends when they fall inside a device's supported range
+const STANDARD_SAMPLE_RATES: [u32; 9] = [
+    8_000, 11_025, 16_000, 22_050, 24_000, 32_000, 44_100, 48_000, 96_000,
+];
+
+/// A device and the capabilities reported by the backend
+pub struct DeviceDescription {
+    pub name: String,
+    pub id: DeviceId,
+    pub is_default: bool,
+    pub sample_rates: Vec<u32>,
+    pub channels: Vec<u32>,
+    pub formats: Vec<Format>,
+}
+impl DeviceDescription {
+    fn new(name: String, id: DeviceId, info: &DeviceInfo) -> Self {
+        let sample_rates = STANDARD_SAMPLE_RATES
+            .iter()
+            .filter(|&&rate| rate >= info.min_sample_rate() && rate <= info.max_sample_rate())
+            .cloned()
+            .collect();
+        DeviceDescription {
+            name,
+            id,
+            is_default: Self::is_default(info),
+            sample_rates,
+            channels: (info.min_channels()..=info.max_channels()).collect(),
+            formats: info.formats().to_vec(),
+        }
+    }
+
+    fn without_info(name: String, id: DeviceId) -> Self {
+        DeviceDescription {
+            name,
+            id,
+            is_default: false,
+            sample_rates: Vec::new(),
+            channels: Vec::new(),
+            formats: Vec::new(),
+        }
+    }
+
+    /// miniaudio 0.10 doesn't expose the default flag of a device, it's read from the
+    /// `_private.isDefault` field of the ma_device_info binding that DeviceInfo wraps
+    fn is_default(info: &DeviceInfo) -> bool {
+        //DeviceInfo is a #[repr(transparent)] wrapper of ma_device_info
+        let info = unsafe { &*(info as *const DeviceInfo as *const miniaudio_sys::ma_device_info) };
+        info._private.isDefault != 0
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "id": Audio::device_id_to_text(&self.id),
+            "name": self.name,
+            "default": self.is_default,
+            "sample_rates": self.sample_rates,
+            "channels": self.channels,
+            "formats": self.formats.iter().map(|&f| Audio::format_to_text(f)).collect::<Vec<&str>>(),
+        })
+    }
+}
+
+/// The backend names op_code 4 and --backend take
+const BACKEND_NAMES: [&str; 14] = [
+    "PulseAudio",
+    "ALSA",
+    "JACK",
+    "CoreAudio",
+    "Wasapi",
+    "DirectSound",
+    "WinMM",
+    "Audio4",
+    "OSS",
+    "OpenSL",
+    "sndio",
+    "AAudio",
+    "WebAudio",
+    "Null",
+];
+
+/// Context shared by every device, created on first use with the default backend order
+static CONTEXT: Mutex<Option<Context>> = Mutex::new(None);
+/// Backend priority list used when the context is (re)created, empty means miniaudio's default
+static BACKENDS: Mutex<Vec<Backend>> = Mutex::new(Vec::new());
+
 pub struct Audio {}
 impl Audio {
+    /// Returns the shared miniaudio context, creating it if needed
+    pub fn context() -> Context {
+        let mut context = CONTEXT.lock().unwrap();
+        if context.is_none() {
+            let backends = BACKENDS.lock().unwrap();
+            *context = Some(Context::new(&backends, None).unwrap());
+        }
+        context.as_ref().unwrap().clone()
+    }
+
+    /// Re-creates the shared context with the given backend priority list.
+    /// Devices created after this call will use the new context.
+    /// # Arguments
+    /// * `backends` - The backends to try in order, empty for miniaudio's default
+    /// # Returns
+    /// * `Backend` - The backend that was actually selected
+    pub fn set_backends(backends: Vec<Backend>) -> Result<Backend, Error> {
+        let context = Context::new(&backends, None)?;
+        let backend = context.backend();
+        *BACKENDS.lock().unwrap() = backends;
+        *CONTEXT.lock().unwrap() = Some(context);
+        Ok(backend)
+    }
+
     /// Returns all the capture devices
     pub fn get_input_devices() -> Vec<(String, DeviceId)> {
-        let context = Context::new(&[], None).unwrap();
+        let context = Self::context();
         let mut inputs: Vec<(String, DeviceId)> = Vec::new();
 
         context
@@ -30,7 +184,7 @@ impl Audio {
 
     /// Returns all the playback devices
     pub fn get_output_devices() -> Vec<(String, DeviceId)> {
-        let context = Context::new(&[], None).unwrap();
+        let context = Self::context();
         let mut outputs: Vec<(String, DeviceId)> = Vec::new();
 
         context
@@ -43,62 +197,221 @@ impl Audio {
         outputs
     }
 
-    /// Prints all the capture and playback devices (used for debugging)
-    pub fn print_devices() {
-        let context = Context::new(&[], None).unwrap();
-
+    /// Returns the devices of the given kind along with their capabilities
+    pub fn get_device_descriptions(kind: DeviceKind) -> Vec<DeviceDescription> {
+        let context = Self::context();
+        let device_type = match kind {
+            DeviceKind::Capture => DeviceType::Capture,
+            DeviceKind::Playback => DeviceType::Playback,
+        };
+        let mut ids: Vec<(String, DeviceId)> = Vec::new();
         context
             .with_devices(|playback_devices, capture_devices| {
-                println!("Playback Devices:");
-                for (idx, device) in playback_devices.iter().enumerate() {
-                    println!("\t{}: {}", idx, device.name());
+                let devices = match kind {
+                    DeviceKind::Capture => capture_devices,
+                    DeviceKind::Playback => playback_devices,
+                };
+                for device in devices.iter() {
+                    ids.push((device.name().to_string(), device.id().clone()));
                 }
+            })
+            .expect("failed to get devices");
 
-                println!("Capture Devices:");
-                for (idx, device) in capture_devices.iter().enumerate() {
-                    println!("\t{}: {}", idx, device.name());
+        ids.into_iter()
+            .map(|(name, id)| {
+                //Enumeration only fills the id and name, ask the backend for the rest
+                match context.get_device_info(device_type, &id, ShareMode::Shared) {
+                    Ok(info) => DeviceDescription::new(name, id, &info),
+                    Err(e) => {
+                        debug!("Failed to get info for device {}: {}", name, e);
+                        DeviceDescription::without_info(name, id)
+                    }
                 }
             })
-            .expect("failed to get devices");
+            .collect()
+    }
+
+    /// Returns the backend and every capture and playback device as a json value
+    pub fn devices_json() -> Value {
+        let playback = Self::get_device_descriptions(DeviceKind::Playback);
+        let capture = Self::get_device_descriptions(DeviceKind::Capture);
+        json!({
+            "backend": Self::backend_to_text(Self::context().backend()),
+            "playback": playback.iter().map(|d| d.to_json()).collect::<Vec<Value>>(),
+            "capture": capture.iter().map(|d| d.to_json()).collect::<Vec<Value>>(),
+        })
     }
 
-    pub fn get_device_id(name: &String, kind: DeviceKind) -> Option<DeviceId> {
-        let context = Context::new(&[], None).unwrap();
-        let mut device_id = None;
+    /// Prints all the capture and playback devices as an event
+    pub fn print_devices() {
+        let mut event = Self::devices_json();
+        event["event_code"] = json!(5);
+        println!("{}", event);
+    }
+
+    /// Serializes a DeviceId so it can be handed to a front-end and parsed back.
+    /// The whole id is encoded, an id of zeros (the first device of some backends) is still an id.
+    pub fn device_id_to_text(id: &DeviceId) -> String {
+        let bytes = unsafe {
+            std::slice::from_raw_parts(
+                id as *const DeviceId as *const u8,
+                std::mem::size_of::<DeviceId>(),
+            )
+        };
+        BASE64.encode(bytes)
+    }
+
+    pub fn format_to_text(format: Format) -> &'static str {
+        match format {
+            Format::U8 => "u8",
+            Format::S16 => "s16",
+            Format::S24 => "s24",
+            Format::S32 => "s32",
+            Format::F32 => "f32",
+            Format::Unknown => "unknown",
+        }
+    }
+
+    /// Finds a device by serialized id, name or index (in that order).
+    /// `_` or `default` select the backend's default device.
+    /// # Arguments
+    /// * `selector` - The serialized id, name or index of the device
+    /// * `kind` - Whether to look for a capture or a playback device
+    /// # Returns
+    /// * `Option<DeviceId>` - The id of the device or None for the default device
+    /// # Errors
+    /// * `DeviceError` - If no device or more than one device matches
+    pub fn get_device_id(selector: &str, kind: DeviceKind) -> Result<Option<DeviceId>, DeviceError> {
+        if selector == "_" || selector == "default" {
+            return Ok(None);
+        }
+        let context = Self::context();
+        let mut devices: Vec<(String, DeviceId)> = Vec::new();
         context
             .with_devices(|playback_devices, capture_devices| {
-                if kind == DeviceKind::Capture {
-                    for device in capture_devices.iter() {
-                        if device.name() == name {
-                            device_id = Some(device.id().clone());
-                        }
-                    }
-                } else {
-                    for device in playback_devices.iter() {
-                        if device.name() == name {
-                            device_id = Some(device.id().clone());
-                        }
-                    }
+                let listed = match kind {
+                    DeviceKind::Capture => capture_devices,
+                    DeviceKind::Playback => playback_devices,
+                };
+                for device in listed.iter() {
+                    devices.push((device.name().to_string(), device.id().clone()));
                 }
             })
             .expect("failed to get devices");
-        device_id
+
+        if let Some((_, id)) = devices
+            .iter()
+            .find(|(_, id)| Self::device_id_to_text(id) == selector)
+        {
+            return Ok(Some(id.clone()));
+        }
+
+        let named: Vec<&DeviceId> = devices
+            .iter()
+            .filter(|(name, _)| name == selector)
+            .map(|(_, id)| id)
+            .collect();
+        match named.len() {
+            0 => {}
+            1 => return Ok(Some(named[0].clone())),
+            _ => return Err(DeviceError::Ambiguous(selector.to_string(), kind)),
+        }
+
+        if let Ok(index) = selector.parse::<usize>() {
+            if let Some((_, id)) = devices.get(index) {
+                return Ok(Some(id.clone()));
+            }
+        }
+        Err(DeviceError::NotFound(selector.to_string(), kind))
+    }
+
+    /// Prints a device error as an event
+    pub fn print_device_error(error: &DeviceError) {
+        let (device, kind) = match error {
+            DeviceError::NotFound(device, kind) => (device, kind),
+            DeviceError::Ambiguous(device, kind) => (device, kind),
+            DeviceError::Backend(device, kind, _) => (device, kind),
+            DeviceError::Codec(device, kind, _) => (device, kind),
+        };
+        println!(
+            "{}",
+            json!({
+                "event_code": 6,
+                "device": device,
+                "kind": kind.to_text(),
+                "error": error.to_string(),
+            })
+        );
+    }
+
+    /// Prints a successful device switch as an event
+    pub fn print_device_changed(kind: DeviceKind, selection: &DeviceSelection) {
+        println!(
+            "{}",
+            json!({
+                "event_code": 9,
+                "kind": kind.to_text(),
+                "device": selection.device,
+                "channels": selection.channels,
+                "sample_rate": selection.sample_rate,
+            })
+        );
+    }
+
+    /// Parses a comma separated backend list like "PulseAudio,ALSA"
+    /// # Errors
+    /// * The first unknown backend, with the names that are known
+    pub fn backends_from_text(backends: &str) -> Result<Vec<Backend>, String> {
+        backends
+            .split(',')
+            .map(|backend| backend.trim())
+            .filter(|backend| !backend.is_empty())
+            .map(|backend| {
+                Self::backend_from_text(backend.to_string()).ok_or_else(|| {
+                    format!("Unknown audio backend {}, expected one of {}", backend, BACKEND_NAMES.join(", "))
+                })
+            })
+            .collect()
     }
-    pub fn backend_from_text(backend: String) -> Backend {
+
+    /// Reads a backend name of BACKEND_NAMES, None if it's unknown
+    pub fn backend_from_text(backend: String) -> Option<Backend> {
         match backend.as_str() {
-            "PulseAudio" => Backend::PulseAudio,
-            "ALSA" => Backend::Alsa,
-            "JACK" => Backend::Jack,
-            "CoreAudio" => Backend::CoreAudio,
-            "Wasapi" => Backend::Wasapi,
-            "DirectSound" => Backend::DSound,
-            "WinMM" => Backend::WinMM,
-            "DSound" => Backend::DSound,
-            "Audio4" => Backend::Audio4,
-            "OSS" => Backend::OSS,
-            "OpenSL" => Backend::OpenSL,
-            "sndio" => Backend::SNDIO,
-            _ => Backend::Null,
+            "PulseAudio" => Some(Backend::PulseAudio),
+            "ALSA" => Some(Backend::Alsa),
+            "JACK" => Some(Backend::Jack),
+            "CoreAudio" => Some(Backend::CoreAudio),
+            "Wasapi" => Some(Backend::Wasapi),
+            "DirectSound" => Some(Backend::DSound),
+            "WinMM" => Some(Backend::WinMM),
+            "DSound" => Some(Backend::DSound),
+            "Audio4" => Some(Backend::Audio4),
+            "OSS" => Some(Backend::OSS),
+            "OpenSL" => Some(Backend::OpenSL),
+            "sndio" => Some(Backend::SNDIO),
+            "AAudio" => Some(Backend::AAudio),
+            "WebAudio" => Some(Backend::WebAudio),
+            "Null" => Some(Backend::Null),
+            _ => None,
+        }
+    }
+
+    pub fn backend_to_text(backend: Backend) -> &'static str {
+        match backend {
+            Backend::PulseAudio => "PulseAudio",
+            Backend::Alsa => "ALSA",
+            Backend::Jack => "JACK",
+            Backend::CoreAudio => "CoreAudio",
+            Backend::Wasapi => "Wasapi",
+            Backend::DSound => "DirectSound",
+            Backend::WinMM => "WinMM",
+            Backend::Audio4 => "Audio4",
+            Backend::OSS => "OSS",
+            Backend::OpenSL => "OpenSL",
+            Backend::SNDIO => "sndio",
+            Backend::AAudio => "AAudio",
+            Backend::WebAudio => "WebAudio",
+            Backend::Null => "Null",
         }
     }
 }
@@ -8,88 +8,84 @@ use std::{
     thread,
 };
 
-use miniaudio::{Device, DeviceConfig, DeviceType, Format, ShareMode};
 use opus::{Channels, Decoder};
 
-use crate::audio::Audio;
-use crate::audio::DeviceKind;
+use crate::audio::device::{DeviceSelection, ManagedDevice};
+use crate::audio::{DeviceError, DeviceKind};
 use crate::spawn_thread;
 
 pub struct AudioPlayback {
-    playback_device: Device,
+    playback_device: ManagedDevice,
     playback_tx: Sender<Bytes>,
     playback_rx: Receiver<Bytes>,
     playback_arc: Arc<Mutex<Vec<Bytes>>>,
+    decoder: Arc<Mutex<Decoder>>,
 }
 impl AudioPlayback {
-    /// Creates a DeviceConfig for a playback device
-    /// # Arguments
-    /// * `device_name` - The name of the device to use
-    /// * `channels` - The number of channels to use
-    /// * `sample_rate` - The sample rate to use
-    pub fn create_config(device_name: &String, channels: u32, sample_rate: u32) -> DeviceConfig {
-        let device_id = Audio::get_device_id(device_name, DeviceKind::Playback);
-        let mut config = DeviceConfig::new(DeviceType::Playback);
-        config.playback_mut().set_format(Format::S16);
-        config.playback_mut().set_channels(channels);
-        config.playback_mut().set_share_mode(ShareMode::Shared);
-        config.playback_mut().set_device_id(device_id);
-        config.set_sample_rate(sample_rate);
-        //config.set_period_size_in_milliseconds(10);
-        config
-    }
-
     /// Creates a new AudioPlayback instance
     /// # Arguments
-    /// * `config` - The DeviceConfig to use
-    pub fn new(config: DeviceConfig) -> Self {
+    /// * `selection` - The device, channels and sample rate to use
+    pub fn new(selection: DeviceSelection) -> Result<Self, DeviceError> {
         let (playback_tx, playback_rx) = flume::unbounded::<Bytes>();
         let playback_arc = Arc::new(Mutex::new(Vec::<Bytes>::new()));
         let playback_clone = playback_arc.clone();
 
-        let decoder_channels = match config.playback().channels() {
-            1 => Channels::Mono,
-            2 => Channels::Stereo,
-            _ => panic!("Invalid channel count"),
-        };
-        let mut decoder = Decoder::new(config.sample_rate(), decoder_channels).unwrap();
-
-        let mut playback_device: Device = Device::new(None, &config).unwrap();
-        playback_device.set_data_callback(move |_, output, _| {
-            let mut queue = playback_clone.lock().unwrap();
-            let samples_len = output.as_samples_mut::<i16>().len();
-            let decoded_buf = &mut [0; 1024];
-
-            if queue.len() > 1 {
-                //Decode opus packet
-                let payload = queue.remove(0);
-                let _ = decoder
-                    .decode(&payload[..payload.len() - 1], decoded_buf, false)
-                    .unwrap();
-                let decoded = &mut decoded_buf[..samples_len];
-
-                //Apply volume by scaling the decoded samples
-                let volume = payload[payload.len() - 1] as f32 / 100.0;
-                decoded
-                    .iter_mut()
-                    .for_each(|x| *x = (*x as f32 * volume) as i16);
-
-                //Copy the decoded samples to the output buffer
-                output.as_samples_mut::<i16>().copy_from_slice(&decoded);
-            }
-        });
-        AudioPlayback {
+        let decoder = Arc::new(Mutex::new(Self::create_decoder(&selection)?));
+        let decoder_clone = decoder.clone();
+
+        let playback_device = ManagedDevice::new(
+            DeviceKind::Playback,
+            selection,
+            Box::new(move |_, output, _| {
+                let mut queue = playback_clone.lock().unwrap();
+                let samples_len = output.as_samples_mut::<i16>().len();
+                let decoded_buf = &mut [0; 2048];
+
+                if queue.len() > 1 {
+                    //Decode opus packet
+                    let payload = queue.remove(0);
+                    let _ = decoder_clone
+                        .lock()
+                        .unwrap()
+                        .decode(&payload[..payload.len() - 1], decoded_buf, false)
+                        .unwrap();
+                    let decoded = &mut decoded_buf[..samples_len];
+
+                    //Apply volume by scaling the decoded samples
+                    let volume = payload[payload.len() - 1] as f32 / 100.0;
+                    decoded
+                        .iter_mut()
+                        .for_each(|x| *x = (*x as f32 * volume) as i16);
+
+                    //Copy the decoded samples to the output buffer
+                    output.as_samples_mut::<i16>().copy_from_slice(decoded);
+                }
+            }),
+        )?;
+        Ok(AudioPlayback {
             playback_device,
             playback_tx,
             playback_rx,
             playback_arc,
-        }
+            decoder,
+        })
+    }
+
+    /// Creates an opus decoder matching the playback format
+    fn create_decoder(selection: &DeviceSelection) -> Result<Decoder, DeviceError> {
+        let codec_error =
+            |e: String| DeviceError::Codec(selection.device.clone(), DeviceKind::Playback, e);
+        let decoder_channels = match selection.channels {
+            1 => Channels::Mono,
+            2 => Channels::Stereo,
+            n => return Err(codec_error(format!("invalid channel count {}", n))),
+        };
+        Decoder::new(selection.sample_rate, decoder_channels).map_err(|e| codec_error(e.to_string()))
     }
 
     /// Starts the playback device
     pub fn start(&self) {
-        let err = self.playback_device.start();
-        if let Err(e) = err {
+        if let Err(e) = self.playback_device.start() {
             error!("Error starting playback device: {}", e);
         }
         let playback_arc = self.playback_arc.clone();
@@ -97,10 +93,8 @@ impl AudioPlayback {
 
         spawn_thread!("playback device playstream listener", move || loop {
             if let Ok(payload) = playback_rx.recv() {
-                if payload.len() == 1 {
-                    if payload[0] == 0 {
-                        break;
-                    }
+                if payload.len() == 1 && payload[0] == 0 {
+                    break;
                 }
                 let mut queue = playback_arc.lock().unwrap();
                 queue.push(payload);
@@ -118,41 +112,29 @@ impl AudioPlayback {
         self.playback_tx.clone()
     }
 
-    pub fn change_device(&mut self, device_name: &String, channels: u32, sample_rate: u32) {
-        self.stop();
-        let playback_clone = self.playback_arc.clone();
-        let config = Self::create_config(device_name, channels, sample_rate);
-
-        let decoder_channels = match config.playback().channels() {
-            1 => Channels::Mono,
-            2 => Channels::Stereo,
-            _ => panic!("Invalid channel count"),
-        };
-        let mut decoder = Decoder::new(config.sample_rate(), decoder_channels).unwrap();
-
-        let mut playback_device: Device = Device::new(None, &config).unwrap();
-        playback_device.set_data_callback(move |_, output, _| {
-            let mut queue = playback_clone.lock().unwrap();
-            let samples_len = output.as_samples_mut::<i16>().len();
-            let decoded_buf = &mut [0; 2048];
-
-            if queue.len() > 1 {
-                //Decode opus packet
-                let payload = queue.remove(0);
-                let _ = decoder
-                    .decode(&payload[..payload.len() - 1], decoded_buf, false)
-                    .unwrap();
-                let decoded = &mut decoded_buf[..samples_len];
-
-                //Apply volume by scaling the decoded samples
-                let volume = payload[payload.len() - 1] as f32 / 100.0;
-                decoded
-                    .iter_mut()
-                    .for_each(|x| *x = (*x as f32 * volume) as i16);
+    /// Switches to another playback device, the decoder and the queued packets are kept
+    /// unless the format changes. On failure the previous device keeps playing.
+    /// # Arguments
+    /// * `selection` - The device, channels and sample rate to switch to
+    pub fn change_device(&mut self, selection: DeviceSelection) -> Result<(), DeviceError> {
+        if selection.same_format(self.playback_device.get_selection()) {
+            return self.playback_device.switch(selection);
+        }
 
-                //Copy the decoded samples to the output buffer
-                output.as_samples_mut::<i16>().copy_from_slice(&decoded);
-            }
-        });
+        let decoder = Self::create_decoder(&selection)?;
+        //Swap the decoder while no device can run the callback
+        let was_started = self.playback_device.is_started();
+        if was_started {
+            self.playback_device.stop()?;
+        }
+        let previous = std::mem::replace(&mut *self.decoder.lock().unwrap(), decoder);
+        let switched = self.playback_device.switch(selection);
+        if switched.is_err() {
+            *self.decoder.lock().unwrap() = previous;
+        }
+        if was_started {
+            self.playback_device.start()?;
+        }
+        switched
     }
 }
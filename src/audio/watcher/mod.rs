@@ -0,0 +1,155 @@
+// SPDX-FileCopyrightText: Copyright 2023 tSVoI
+// SPDX-License-Identifier: GPL-3.0-only
+
+use flume::{Receiver, Sender};
+use serde_json::json;
+use std::{collections::HashMap, thread, time::Duration};
+
+use crate::audio::{Audio, DeviceKind};
+use crate::spawn_thread;
+
+#[derive(Clone, Debug)]
+pub enum DeviceEvent {
+    /// A device appeared: kind, serialized id, name
+    Added(DeviceKind, String, String),
+    /// A device disappeared: kind, serialized id
+    Removed(DeviceKind, String),
+}
+
+pub struct DeviceWatcher {
+    event_tx: Sender<DeviceEvent>,
+    event_rx: Receiver<DeviceEvent>,
+}
+impl DeviceWatcher {
+    /// Creates a new DeviceWatcher
+    pub fn new() -> Self {
+        let (event_tx, event_rx) = flume::unbounded();
+        DeviceWatcher { event_tx, event_rx }
+    }
+
+    /// Polls the device lists and prints an event for every device that was plugged or unplugged
+    /// # Arguments
+    /// * `interval` - Time between two polls
+    pub fn start(&self, interval: Duration) {
+        let event_tx = self.event_tx.clone();
+        spawn_thread!("audio device watcher", move || {
+            let mut known = Self::snapshot();
+            loop {
+                thread::sleep(interval);
+                let current = Self::snapshot();
+                for (key, name) in current.iter() {
+                    if !known.contains_key(key) {
+                        Self::print_event("added", key.0, &key.1, name);
+                        let _ = event_tx.send(DeviceEvent::Added(key.0, key.1.clone(), name.clone()));
+                    }
+                }
+                for (key, name) in known.iter() {
+                    if !current.contains_key(key) {
+                        Self::print_event("removed", key.0, &key.1, name);
+                        let _ = event_tx.send(DeviceEvent::Removed(key.0, key.1.clone()));
+                    }
+                }
+                known = current;
+            }
+        });
+    }
+
+    /// Returns the device event receiver
+    pub fn get_event_rx(&self) -> Receiver<DeviceEvent> {
+        self.event_rx.clone()
+    }
+
+    fn snapshot() -> HashMap<(DeviceKind, String), String> {
+        let mut devices = HashMap::new();
+        for (name, id) in Audio::get_input_devices() {
+            devices.insert((DeviceKind::Capture, Audio::device_id_to_text(&id)), name);
+        }
+        for (name, id) in Audio::get_output_devices() {
+            devices.insert((DeviceKind::Playback, Audio::device_id_to_text(&id)), name);
+        }
+        devices
+    }
+
+    fn print_event(change: &str, kind: DeviceKind, id: &str, name: &str) {
+        println!(
+            "{}",
+            json!({
+                "event_code": 7,
+                "change": change,
+                "kind": kind.to_text(),
+                "id": id,
+                "name": name,
+            })
+        );
+    }
+}
+
+/// Switches to the default device when the preferred one is unplugged
+/// and back to the preferred one when it returns
+pub struct FailoverPolicy {
+    preferred: HashMap<DeviceKind, (String, String)>,
+    failed_over: HashMap<DeviceKind, bool>,
+}
+impl FailoverPolicy {
+    pub fn new() -> Self {
+        FailoverPolicy {
+            preferred: HashMap::new(),
+            failed_over: HashMap::new(),
+        }
+    }
+
+    /// Remembers the device the user asked for, the default device needs no failover
+    /// # Arguments
+    /// * `selector` - The id, name or index the device was selected with
+    /// * `kind` - Whether it's the capture or the playback device
+    pub fn set_preferred(&mut self, selector: &str, kind: DeviceKind) {
+        self.failed_over.insert(kind, false);
+        match Audio::get_device_id(selector, kind) {
+            Ok(Some(id)) => {
+                let id = Audio::device_id_to_text(&id);
+                let name = Self::find_name(&id, kind).unwrap_or_default();
+                self.preferred.insert(kind, (id, name));
+            }
+            _ => {
+                self.preferred.remove(&kind);
+            }
+        }
+    }
+
+    /// Decides if a device event requires switching devices
+    /// # Returns
+    /// * `Option<(DeviceKind, String)>` - The kind and selector of the device to switch to
+    pub fn on_event(&mut self, event: &DeviceEvent) -> Option<(DeviceKind, String)> {
+        match event {
+            DeviceEvent::Removed(kind, id) => {
+                let (preferred_id, _) = self.preferred.get(kind)?;
+                if preferred_id != id || self.failed_over[kind] {
+                    return None;
+                }
+                self.failed_over.insert(*kind, true);
+                Some((*kind, "_".to_string()))
+            }
+            DeviceEvent::Added(kind, id, name) => {
+                let (preferred_id, preferred_name) = self.preferred.get(kind)?;
+                //Some backends hand out a new id when the device is plugged back in
+                if (preferred_id != id && preferred_name != name) || !self.failed_over[kind] {
+                    return None;
+                }
+                self.failed_over.insert(*kind, false);
+                self.preferred.insert(*kind, (id.clone(), name.clone()));
+                Some((*kind, id.clone()))
+            }
+        }
+    }
+
+    fn find_name(id: &str, kind: DeviceKind) -> Option<String> {
+        let devices = match kind {
+            DeviceKind::Capture => Audio::get_input_devices(),
+            DeviceKind::Playback => Audio::get_output_devices(),
+        };
+        devices
+            .into_iter()
+            .find(|(_, device_id)| Audio::device_id_to_text(device_id) == id)
+            .map(|(name, _)| name)
+    }
+}
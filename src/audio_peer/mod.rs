@@ -5,125 +5,478 @@ use bytes::{BufMut, Bytes, BytesMut};
 use std::{
     cmp::Reverse,
     collections::BinaryHeap,
+    net::SocketAddr,
     sync::{
-        atomic::{AtomicBool, AtomicI8, AtomicU64, Ordering},
+        atomic::{AtomicBool, AtomicI8, AtomicU32, AtomicU64, Ordering},
         Arc, Mutex,
     },
     thread,
+    time::{Duration, Instant},
 };
 
+use flume::{Receiver, Sender};
+
+pub mod mux;
+
 use crate::aes::AES;
 use crate::audio::playback::AudioPlayback;
+use crate::audio::device::DeviceSelection;
+use crate::audio::{Audio, DeviceError};
+use crate::audio_peer::mux::UdpMux;
+use crate::ice::{self, Candidate, CandidatePair, SelectedPath};
+use crate::signaling::punch::PUNCH_ATTEMPTS;
+use crate::signaling::sfu::ROOM_CONNECTION;
+use crate::roster;
+use crate::signaling::PeerId;
 use crate::spawn_thread;
 
+/// Where voice packets go
+struct Path {
+    /// Index of the mux socket
+    socket: usize,
+    addr: SocketAddr,
+    /// Prefixed to every packet when the path goes through the server relay
+    relay_token: Option<u64>,
+}
+
+impl Path {
+    /// Sends a packet to the peer, through the relay if the path goes through it
+    fn send(&self, mux: &UdpMux, remote_connection: u32, packet: &[u8]) -> Result<usize, std::io::Error> {
+        match self.relay_token {
+            Some(token) => mux.send_relayed(self.socket, token, remote_connection, packet, self.addr),
+            None => mux.send_to(self.socket, remote_connection, packet, self.addr),
+        }
+    }
+}
+
+/// A relay the server offered for a peer
+#[derive(Clone)]
+struct RelayOffer {
+    /// The relay addresses, one per family
+    addrs: Vec<SocketAddr>,
+    /// The token the relay knows us by
+    token: u64,
+}
+
+/// Time between two relay refreshes, keeps the NAT mapping to the relay open while muted
+const RELAY_REFRESH: Duration = Duration::from_secs(10);
+/// Time between two keepalives
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(1);
+/// Silence after which a peer is degraded
+const DEGRADED_AFTER: Duration = Duration::from_secs(3);
+/// Silence after which a peer is lost
+const LOST_AFTER: Duration = Duration::from_secs(10);
+/// Round trip time above which a peer is degraded
+const DEGRADED_RTT: Duration = Duration::from_millis(400);
+
+/// The health of the udp path to a peer
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum PeerState {
+    /// Connectivity checks are running
+    Connecting,
+    Connected,
+    /// Packets stopped arriving for a moment or the round trip time is high
+    Degraded,
+    /// Packets stopped arriving
+    Lost,
+}
+impl PeerState {
+    pub fn to_text(self) -> &'static str {
+        match self {
+            PeerState::Connecting => "connecting",
+            PeerState::Connected => "connected",
+            PeerState::Degraded => "degraded",
+            PeerState::Lost => "lost",
+        }
+    }
+}
+
 pub struct AudioPeer {
+    id: PeerId,
     ready: Arc<AtomicBool>,
     packet_count: Arc<AtomicU64>,
     volume: Arc<AtomicI8>,
-    udpsocket: Arc<Mutex<std::net::UdpSocket>>,
+    mux: Arc<UdpMux>,
+    /// Our connection id, the peer puts it in front of every packet
+    connection: u32,
+    /// The connection id of the peer, known once it sent its candidates
+    remote_connection: Arc<AtomicU32>,
+    /// The pairs to check, known once the peer sent its candidates
+    pairs: Arc<Mutex<Vec<CandidatePair>>>,
+    /// The relay the server offered for this peer, checked with the other pairs
+    relay: Arc<Mutex<Option<RelayOffer>>>,
+    /// Binding responses read by the mux
+    responses_tx: Sender<(u64, SocketAddr)>,
+    responses_rx: Receiver<(u64, SocketAddr)>,
+    /// The socket and the address voice packets are sent to
+    path: Arc<Mutex<Option<Path>>>,
     aes: AES,
     device: Arc<Mutex<Option<AudioPlayback>>>,
+    /// Timestamps are microseconds since this instant
+    created: Instant,
+    /// When the last packet of the peer arrived
+    last_received: Arc<AtomicU64>,
+    /// Smoothed round trip time in microseconds, 0 until the first keepalive answer
+    rtt: Arc<AtomicU64>,
 }
 impl AudioPeer {
-    /// Creates a new AudioPeer
+    /// Creates a new AudioPeer on the shared udp sockets
     /// # Arguments
-    /// * `bind` - The address to bind to
-    pub fn new(bind: String, key: String) -> AudioPeer {
+    /// * `id` - The signaling id of the peer
+    /// * `key` - The key shared by the room
+    pub fn new(id: PeerId, key: String) -> AudioPeer {
         debug!("Creating AudioPeer");
+        let mux = UdpMux::shared();
+        let connection = mux.allocate();
+        let (responses_tx, responses_rx) = flume::unbounded();
         AudioPeer {
+            id,
             packet_count: Arc::new(AtomicU64::new(0)),
             ready: Arc::new(AtomicBool::new(false)),
             volume: Arc::new(AtomicI8::new(100)),
             //tk_socketqueue: Arc::new(Mutex::new(BinaryHeap::new())),
-            udpsocket: Arc::new(Mutex::new(
-                std::net::UdpSocket::bind(bind).expect("couldn't bind to address"),
-            )),
+            mux,
+            connection,
+            remote_connection: Arc::new(AtomicU32::new(0)),
+            pairs: Arc::new(Mutex::new(Vec::new())),
+            relay: Arc::new(Mutex::new(None)),
+            responses_tx,
+            responses_rx,
+            path: Arc::new(Mutex::new(None)),
             aes: AES::new(Some(&key)).unwrap(),
             device: Arc::new(Mutex::new(None)),
+            created: Instant::now(),
+            last_received: Arc::new(AtomicU64::new(0)),
+            rtt: Arc::new(AtomicU64::new(0)),
         }
     }
 
-    // TODO: change playback_config to an AudioPlayback object
-    /// Connects to a peer
+    /// Returns the local candidates, highest priority first
+    pub fn get_candidates(&self) -> &[Candidate] {
+        self.mux.get_candidates()
+    }
+
+    /// Returns the connection id the peer must put in front of its packets
+    pub fn get_connection_id(&self) -> u32 {
+        self.connection
+    }
+
+    /// Connects to a peer, the connectivity checks start with the first punch
     /// # Arguments
-    /// * `addr` - The address to connect to
-    /// * `playback_name` - The name of the playback device
-    pub fn connect(&self, addr: &str, playback_name: &String) {
-        debug!("Connecting to {}", addr);
-        self.udpsocket
-            .lock()
-            .unwrap()
-            .connect(&addr)
-            .expect("couldn't connect to address");
-        let udp_socket = self.udpsocket.lock().unwrap().try_clone().unwrap();
+    /// * `remote` - The candidates of the peer
+    /// * `remote_connection` - The connection id the peer picked
+    /// * `playback` - The playback device to play the peer on
+    pub fn connect(&self, remote: &[Candidate], remote_connection: u32, playback: &DeviceSelection) {
+        debug!("Connecting to {}", ice::candidates_to_text(remote));
+        self.remote_connection.store(remote_connection, Ordering::Relaxed);
+        let audio_playback = match AudioPlayback::new(playback.clone()) {
+            Ok(audio_playback) => audio_playback,
+            Err(e) => {
+                Audio::print_device_error(&e);
+                return;
+            }
+        };
+        audio_playback.start();
+        let playback_tx = audio_playback.get_playback_tx();
+        *self.device.lock().unwrap() = Some(audio_playback);
+
+        //Checks and voice are read on every socket, whichever path the peer picks
         let aes = self.aes.clone();
         let volume = self.volume.clone();
-        let playback_config = AudioPlayback::create_config(playback_name, 2, 48_000);
-        let audio_playback = AudioPlayback::new(playback_config);
-        let ready = self.ready.clone();
-        let device = self.device.clone();
-        //Avoids a weird bug where the cpu usage grows when one of the two peers never receives a packet
-        udp_socket.send(&[1]).unwrap();
-        spawn_thread!("AudioPeer udp", move || {
-            audio_playback.start();
-            let recv_buffer = &mut [0u8; 1024];
-            let mut audio_buffer: BinaryHeap<Reverse<(u64, Bytes)>> = BinaryHeap::new();
-            let playback_tx = audio_playback.get_playback_tx();
-
-            *device.lock().unwrap() = Some(audio_playback);
-            loop {
-                match udp_socket.recv(recv_buffer.as_mut()) {
-                    Ok(n) => {
-                        if n == 1 && !ready.load(Ordering::Relaxed) {
-                            debug!("AudioPeer ready");
-                            ready.store(true, Ordering::Relaxed);
-                            udp_socket.send(&[1]).unwrap();
-                            continue;
+        let responses_tx = self.responses_tx.clone();
+        let mux = self.mux.clone();
+        let path = self.path.clone();
+        let created = self.created;
+        let last_received = self.last_received.clone();
+        let rtt = self.rtt.clone();
+        let relay = self.relay.clone();
+        let id = self.id;
+        let mut audio_buffer: BinaryHeap<Reverse<(u64, Bytes)>> = BinaryHeap::new();
+        self.mux.set_handler(
+            self.connection,
+            Box::new(move |udp_socket, packet, source| {
+                //Decrypt packet
+                let try_decrypt = aes.decrypt(bytes::Bytes::copy_from_slice(packet));
+                if try_decrypt.is_err() {
+                    return;
+                }
+                let decrypted = try_decrypt.unwrap();
+                last_received.store(created.elapsed().as_micros() as u64, Ordering::Relaxed);
+                match decrypted.first() {
+                    Some(&ice::BINDING_REQUEST) => {
+                        if let Some(response) = ice::binding_response(&aes, &decrypted, source) {
+                            let mut framed = BytesMut::with_capacity(response.len() + 12);
+                            //A request that came through the relay is answered through it
+                            if let Some(relay) = relay.lock().unwrap().as_ref() {
+                                if relay.addrs.contains(&source) {
+                                    framed.put_u64(relay.token);
+                                }
+                            }
+                            framed.put_u32(remote_connection);
+                            framed.put(response);
+                            let _ = udp_socket.send_to(&framed, source);
                         }
-                        if n < 8 {
-                            continue;
+                        return;
+                    }
+                    Some(&ice::BINDING_RESPONSE) => {
+                        if let Some(response) = ice::parse_binding_response(&decrypted) {
+                            let _ = responses_tx.send(response);
                         }
-
-                        //Decrypt packet
-                        let try_decrypt = aes.decrypt(bytes::Bytes::copy_from_slice(&recv_buffer[..n]));
-                        if try_decrypt.is_err() {
-                            continue;
+                        return;
+                    }
+                    //Echo the timestamp back on our own path, which may go through the relay
+                    Some(&ice::KEEPALIVE) if decrypted.len() == 9 => {
+                        let mut ack = BytesMut::with_capacity(9);
+                        ack.put_u8(ice::KEEPALIVE_ACK);
+                        ack.put(&decrypted[1..9]);
+                        let encrypted = aes.encrypt(ack.freeze()).unwrap();
+                        if let Some(path) = path.lock().unwrap().as_ref() {
+                            let _ = path.send(&mux, remote_connection, &encrypted);
                         }
-                        let decrypted = try_decrypt.unwrap();
-                        let dec_len = decrypted.len();
-
-                        //Get packet count
-                        let mut packet_count_bytes = [0u8; 8];
-                        packet_count_bytes.copy_from_slice(&decrypted[dec_len - 8..]);
-                        let recv_packet_count: u64 = u64::from_be_bytes(packet_count_bytes);
-
-                        //Push voice packet to buffer
-                        let mut opus = BytesMut::with_capacity(dec_len - 7);
-                        opus.put(&decrypted[..dec_len - 8]);
-                        opus.put_u8(volume.load(Ordering::Relaxed) as u8);
-
-                        let voice = (recv_packet_count, opus.freeze());
-                        audio_buffer.push(Reverse(voice));
+                        return;
                     }
-                    Err(_) => {
+                    Some(&ice::KEEPALIVE_ACK) if decrypted.len() == 9 => {
+                        let mut sent_at = [0u8; 8];
+                        sent_at.copy_from_slice(&decrypted[1..9]);
+                        let sample = (created.elapsed().as_micros() as u64).saturating_sub(u64::from_be_bytes(sent_at));
+                        //Smoothed like TCP does, 7/8 of the previous value
+                        let previous = rtt.load(Ordering::Relaxed);
+                        let smoothed = if previous == 0 { sample } else { (previous * 7 + sample) / 8 };
+                        rtt.store(smoothed, Ordering::Relaxed);
                         return;
-                        //error!("Error: {}", e);
                     }
+                    Some(&ice::VOICE) if decrypted.len() > 8 => {}
+                    _ => return,
+                }
+                roster::voice(id);
+                if roster::is_deafened() {
+                    return;
                 }
+                let dec_len = decrypted.len();
+
+                //Get packet count
+                let mut packet_count_bytes = [0u8; 8];
+                packet_count_bytes.copy_from_slice(&decrypted[dec_len - 8..]);
+                let recv_packet_count: u64 = u64::from_be_bytes(packet_count_bytes);
+
+                //Push voice packet to buffer
+                let mut opus = BytesMut::with_capacity(dec_len - 8);
+                opus.put(&decrypted[1..dec_len - 8]);
+                opus.put_u8(volume.load(Ordering::Relaxed) as u8);
+
+                let voice = (recv_packet_count, opus.freeze());
+                audio_buffer.push(Reverse(voice));
+
                 // "jitter buffer¿¿¿¿¿ (Ñ)"
-                if audio_buffer.len() > 0 {
-                    while !audio_buffer.is_empty() {
-                        let payload = audio_buffer.pop().unwrap().0.1;
-                        playback_tx.send(payload).unwrap();
+                while let Some(Reverse((_, payload))) = audio_buffer.pop() {
+                    let _ = playback_tx.send(payload);
+                }
+            }),
+        );
+
+        *self.pairs.lock().unwrap() = ice::pair(self.mux.get_sockets(), self.get_candidates(), remote);
+        self.monitor();
+    }
+
+    /// Sends keepalives once a path is chosen and prints the peer state when it changes,
+    /// until the peer is dropped
+    fn monitor(&self) {
+        let weak_path = Arc::downgrade(&self.path);
+        let mux = self.mux.clone();
+        let aes = self.aes.clone();
+        let ready = self.ready.clone();
+        let remote_connection = self.remote_connection.clone();
+        let created = self.created;
+        let last_received = self.last_received.clone();
+        let rtt = self.rtt.clone();
+        let id = self.id;
+        Self::print_state(id, PeerState::Connecting, 0);
+        spawn_thread!("AudioPeer keepalive", move || {
+            let mut state = PeerState::Connecting;
+            let mut connected_at = Duration::ZERO;
+            while let Some(path) = weak_path.upgrade() {
+                if !ready.load(Ordering::Relaxed) {
+                    drop(path);
+                    thread::sleep(KEEPALIVE_INTERVAL);
+                    continue;
+                }
+                let now = created.elapsed();
+                if state == PeerState::Connecting {
+                    connected_at = now;
+                }
+
+                let mut keepalive = BytesMut::with_capacity(9);
+                keepalive.put_u8(ice::KEEPALIVE);
+                keepalive.put_u64(now.as_micros() as u64);
+                let encrypted = aes.encrypt(keepalive.freeze()).unwrap();
+                if let Some(path) = path.lock().unwrap().as_ref() {
+                    let _ = path.send(&mux, remote_connection.load(Ordering::Relaxed), &encrypted);
+                }
+                drop(path);
+
+                //Nothing arrived since the path was chosen counts from that moment
+                let last = Duration::from_micros(last_received.load(Ordering::Relaxed)).max(connected_at);
+                let silence = now.saturating_sub(last);
+                let rtt_us = rtt.load(Ordering::Relaxed);
+                let new_state = if silence >= LOST_AFTER {
+                    PeerState::Lost
+                } else if silence >= DEGRADED_AFTER || Duration::from_micros(rtt_us) >= DEGRADED_RTT {
+                    PeerState::Degraded
+                } else {
+                    PeerState::Connected
+                };
+                if new_state != state {
+                    state = new_state;
+                    Self::print_state(id, state, rtt_us);
+                }
+                thread::sleep(KEEPALIVE_INTERVAL);
+            }
+        });
+    }
+
+    /// Prints the state of the path to a peer
+    fn print_state(id: PeerId, state: PeerState, rtt_us: u64) {
+        println!(
+            "{{ \"event_code\": 14, \"id\": {}, \"state\": \"{}\", \"rtt_ms\": {} }}",
+            id,
+            state.to_text(),
+            rtt_us / 1000
+        );
+        roster::set_state(id, state.to_text(), rtt_us / 1000);
+    }
+
+    /// Runs a round of connectivity checks once the delay is over. The signaling server
+    /// sends the same round to the peer, so both NATs open a mapping at the same time.
+    /// # Arguments
+    /// * `delay` - Time to wait before the checks
+    /// * `attempt` - The round, the last round that fails gives up on the peer
+    /// * `on_failure` - Called with the round when it fails and another round is allowed
+    pub fn punch<F>(&self, delay: Duration, attempt: u8, on_failure: F)
+    where
+        F: FnOnce(u8) + Send + 'static,
+    {
+        let mux = self.mux.clone();
+        let pairs = self.pairs.clone();
+        let responses_rx = self.responses_rx.clone();
+        let aes = self.aes.clone();
+        let ready = self.ready.clone();
+        let path = self.path.clone();
+        let remote_connection = self.remote_connection.load(Ordering::Relaxed);
+        let relay = self.relay.lock().unwrap().clone();
+        //The relay is checked like any other candidate of the peer, with the lowest priority
+        let relayed: Vec<Candidate> = relay
+            .iter()
+            .flat_map(|relay| relay.addrs.iter().map(|addr| Candidate::new(ice::CandidateKind::Relayed, *addr)))
+            .collect();
+        let relayed_pairs = ice::pair(self.mux.get_sockets(), self.get_candidates(), &relayed);
+        let token = relay.map(|relay| relay.token);
+        let id = self.id;
+        spawn_thread!("AudioPeer connectivity checks", move || {
+            thread::sleep(delay);
+            if ready.load(Ordering::Relaxed) {
+                return;
+            }
+            //Drop the responses of the previous round
+            responses_rx.drain();
+            let mut pairs = pairs.lock().unwrap().clone();
+            pairs.extend(relayed_pairs);
+            pairs.sort_by_key(|pair| std::cmp::Reverse(pair.priority));
+            let relay_token = |pair: &CandidatePair| token.filter(|_| pair.remote.kind == ice::CandidateKind::Relayed);
+            let send = |pair: &CandidatePair, request: &[u8]| {
+                let _ = match relay_token(pair) {
+                    Some(token) => mux.send_relayed(pair.socket, token, remote_connection, request, pair.remote.addr),
+                    None => mux.send_to(pair.socket, remote_connection, request, pair.remote.addr),
+                };
+            };
+            match ice::check(&pairs, &responses_rx, &aes, send) {
+                Some(selected) => {
+                    Self::print_path(id, &selected);
+                    *path.lock().unwrap() = Some(Path {
+                        socket: selected.pair.socket,
+                        addr: selected.pair.remote.addr,
+                        relay_token: relay_token(&selected.pair),
+                    });
+                    ready.store(true, Ordering::Relaxed);
+                }
+                None => {
+                    debug!("Punch round {} to peer {} failed", attempt, id);
+                    //The relay was checked too, the last round gives up on the peer
+                    if attempt + 1 >= PUNCH_ATTEMPTS {
+                        error!("No candidate pair of peer {} works", id);
+                        println!("{{ \"event_code\": 11, \"id\": {}, \"attempts\": {} }}", id, attempt + 1);
                     }
+                    on_failure(attempt);
                 }
             }
         });
     }
 
-    /// Sends a voice packet through the socket.
+    /// Adds the relay the server offers for this peer, its pairs are checked with the next rounds
+    /// # Arguments
+    /// * `relays` - The relay addresses, one per family
+    /// * `token` - The token the relay knows this peer by
+    pub fn add_relay(&self, relays: &[SocketAddr], token: u64) {
+        *self.relay.lock().unwrap() = Some(RelayOffer {
+            addrs: relays.to_vec(),
+            token,
+        });
+    }
+
+    /// Sends the voice through the server relay without checking any pair, used by forwarding rooms
+    /// # Arguments
+    /// * `relays` - The relay addresses, one per family
+    /// * `token` - The token the relay knows this peer by
+    pub fn use_relay(&self, relays: &[SocketAddr], token: u64) {
+        let path = relays.iter().find_map(|relay| {
+            Some(Path {
+                socket: self.mux.socket_for(relay)?,
+                addr: *relay,
+                relay_token: Some(token),
+            })
+        });
+        let path = match path {
+            Some(path) => path,
+            None => {
+                error!("Can't reach any relay of {:?}", relays);
+                return;
+            }
+        };
+        println!(
+            "{{ \"event_code\": 13, \"id\": {}, \"relay\": \"{}\" }}",
+            self.id, path.addr
+        );
+        roster::set_path(self.id, ice::CandidateKind::Relayed.to_text());
+        let (socket, relay) = (path.socket, path.addr);
+        *self.path.lock().unwrap() = Some(path);
+        self.ready.store(true, Ordering::Relaxed);
+
+        //The relay learns our address from the token, refresh it until the peer is dropped
+        let weak_path = Arc::downgrade(&self.path);
+        let mux = self.mux.clone();
+        spawn_thread!("AudioPeer relay refresh", move || {
+            while weak_path.upgrade().is_some() {
+                let _ = mux.refresh_relay(socket, token, relay);
+                thread::sleep(RELAY_REFRESH);
+            }
+        });
+    }
+
+    /// Prints the pair chosen by the connectivity checks
+    fn print_path(id: PeerId, selected: &SelectedPath) {
+        println!(
+            "{{ \"event_code\": 10, \"id\": {}, \"local\": \"{}\", \"remote\": \"{}\", \"type\": \"{}\", \"rtt_ms\": {} }}",
+            id,
+            selected.mapped,
+            selected.pair.remote.addr,
+            selected.pair.remote.kind.to_text(),
+            selected.rtt.as_millis()
+        );
+        roster::set_path(id, selected.pair.remote.kind.to_text());
+    }
+
+    /// Sends a voice packet through the mux.
     /// The packet is serialized as follows:
-    /// <opus packet variable size><packet number 8 bytes>
+    /// <0><opus packet variable size><packet number 8 bytes>
     /// # Arguments
     /// * `data` - An opus packet
     /// # Returns
@@ -137,18 +490,26 @@ impl AudioPeer {
                 "Peer not ready",
             ));
         }
-        let packet_count = self.packet_count.fetch_add(1, Ordering::Relaxed);
-        let mut payload = BytesMut::with_capacity(data.len() + 8);
-        payload.put(data);
-        payload.put_u64(packet_count);
-        let encrypted = self.aes.encrypt(payload.freeze()).unwrap();
+        let encrypted = voice_packet(&self.aes, data, &self.packet_count);
 
-        self.udpsocket.lock().unwrap().send(&encrypted)
+        let remote_connection = self.remote_connection.load(Ordering::Relaxed);
+        match self.path.lock().unwrap().as_ref() {
+            Some(path) => path.send(&self.mux, remote_connection, &encrypted),
+            None => Err(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                "Peer not ready",
+            )),
+        }
     }
-    pub fn change_device(&self, device_name: &String, channels: u32, sample_rate: u32) {
-        let mut unlock = self.device.lock();
-        let playback = unlock.as_mut().unwrap().as_mut().unwrap();
-        playback.change_device(device_name, channels, sample_rate);
+    /// Switches the playback device of this peer
+    /// # Arguments
+    /// * `selection` - The device, channels and sample rate to switch to
+    pub fn change_device(&self, selection: DeviceSelection) -> Result<(), DeviceError> {
+        match self.device.lock().unwrap().as_mut() {
+            Some(playback) => playback.change_device(selection),
+            //Not connected yet, connect() will use the new device
+            None => Ok(()),
+        }
     }
     pub fn change_volume(&self, volume: u8) {
         self.volume.store(volume as i8, Ordering::Relaxed);
@@ -158,3 +519,67 @@ impl AudioPeer {
         self.ready.load(Ordering::Relaxed)
     }
 }
+/// Serializes and encrypts a voice packet, `<0><opus packet><u64 packet number>`
+fn voice_packet(aes: &AES, data: Bytes, packet_count: &AtomicU64) -> Bytes {
+    let packet_count = packet_count.fetch_add(1, Ordering::Relaxed);
+    let mut payload = BytesMut::with_capacity(data.len() + 9);
+    payload.put_u8(ice::VOICE);
+    payload.put(data);
+    payload.put_u64(packet_count);
+    aes.encrypt(payload.freeze()).unwrap()
+}
+
+/// Our voice in a forwarding room, sent once to the server which copies it to every peer.
+/// The keepalives still go to each peer through the server, on the relayed path of its AudioPeer.
+pub struct ForwardedVoice {
+    mux: Arc<UdpMux>,
+    path: Path,
+    aes: AES,
+    packet_count: AtomicU64,
+}
+impl ForwardedVoice {
+    /// # Arguments
+    /// * `relays` - The addresses of the server, one per family
+    /// * `token` - The token the server knows us by
+    /// * `key` - The key shared by the room
+    /// # Returns
+    /// * None if no address of the server has the family of a local socket
+    pub fn new(relays: &[SocketAddr], token: u64, key: String) -> Option<Self> {
+        let mux = UdpMux::shared();
+        let path = relays.iter().find_map(|relay| {
+            Some(Path {
+                socket: mux.socket_for(relay)?,
+                addr: *relay,
+                relay_token: Some(token),
+            })
+        })?;
+        Some(ForwardedVoice {
+            mux,
+            path,
+            aes: AES::new(Some(&key)).unwrap(),
+            packet_count: AtomicU64::new(0),
+        })
+    }
+
+    /// Returns the addresses and the token the peers' keepalives go through
+    pub fn get_relay(&self) -> (SocketAddr, u64) {
+        (self.path.addr, self.path.relay_token.unwrap_or_default())
+    }
+
+    /// Sends a voice packet to every peer of the room
+    /// # Arguments
+    /// * `data` - An opus packet
+    pub fn send(&self, data: Bytes) -> Result<usize, std::io::Error> {
+        let encrypted = voice_packet(&self.aes, data, &self.packet_count);
+        self.path.send(&self.mux, ROOM_CONNECTION, &encrypted)
+    }
+}
+
+impl Drop for AudioPeer {
+    fn drop(&mut self) {
+        self.mux.release(self.connection);
+        if let Some(playback) = self.device.lock().unwrap().as_ref() {
+            playback.stop();
+        }
+    }
+}
@@ -0,0 +1,207 @@
+// SPDX-FileCopyrightText: Copyright 2023 tSVoI
+// SPDX-License-Identifier: GPL-3.0-only
+
+use aead::rand_core::RngCore;
+use aead::OsRng;
+use bytes::{BufMut, BytesMut};
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::io::Error;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use crate::ice::{self, Candidate};
+use crate::signaling;
+use crate::signaling::sfu::ROOM_CONNECTION;
+use crate::spawn_thread;
+
+/// Reads the packets of a connection
+/// # Arguments
+/// * `socket` - The socket the packet came from, to answer on
+/// * `packet` - The packet without the connection id
+/// * `source` - The address the packet came from
+pub type PacketHandler = dyn FnMut(&UdpSocket, &[u8], SocketAddr) + Send;
+
+static MUX: Mutex<Option<Arc<UdpMux>>> = Mutex::new(None);
+/// The first and last port the sockets may bind to, None for any free port
+static PORT_RANGE: Mutex<Option<(u16, u16)>> = Mutex::new(None);
+
+/// Limits the ports of the sockets, only before the mux is created
+/// # Arguments
+/// * `range` - The first and last port, None for any free port
+pub fn set_port_range(range: Option<(u16, u16)>) {
+    *PORT_RANGE.lock().unwrap() = range;
+}
+
+/// The udp sockets shared by every peer, one per address family, so all the peers
+/// share a NAT mapping. Packets are `<u32 connection id><packet>`, the id is picked
+/// by the receiver and routes the packet to its peer.
+pub struct UdpMux {
+    sockets: Vec<UdpSocket>,
+    candidates: Vec<Candidate>,
+    /// Connections without a handler are reserved but drop their packets
+    handlers: Arc<Mutex<HashMap<u32, Option<Box<PacketHandler>>>>>,
+}
+impl UdpMux {
+    /// Returns the mux of this process, the sockets are bound and their
+    /// candidates gathered on the first call
+    pub fn shared() -> Arc<UdpMux> {
+        let mut mux = MUX.lock().unwrap();
+        if let Some(mux) = mux.as_ref() {
+            return mux.clone();
+        }
+        let created = Arc::new(Self::new());
+        created.start();
+        *mux = Some(created.clone());
+        created
+    }
+
+    fn new() -> Self {
+        let (first, last) = PORT_RANGE.lock().unwrap().unwrap_or((0, 0));
+        let sockets: Vec<UdpSocket> = [IpAddr::from(Ipv6Addr::UNSPECIFIED), IpAddr::from(Ipv4Addr::UNSPECIFIED)]
+            .iter()
+            .filter_map(|ip| {
+                //The ipv6 socket only takes ipv6, both families can get the first port of the range
+                (first..=last)
+                    .find_map(|port| signaling::bind_udp(SocketAddr::new(*ip, port)).ok())
+                    .or_else(|| {
+                        debug!("Couldn't bind to {} on ports {}-{}", ip, first, last);
+                        None
+                    })
+            })
+            .collect();
+        if sockets.is_empty() {
+            error!("Couldn't bind any udp socket, audio won't work");
+        }
+        let candidates = ice::gather(&sockets);
+        debug!("Gathered candidates: {}", ice::candidates_to_text(&candidates));
+        UdpMux {
+            sockets,
+            candidates,
+            handlers: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Starts the receive thread of each socket
+    fn start(&self) {
+        for socket in self.sockets.iter() {
+            let udp_socket = match socket.try_clone() {
+                Ok(udp_socket) => udp_socket,
+                Err(_) => continue,
+            };
+            let handlers = self.handlers.clone();
+            spawn_thread!("udp mux", move || {
+                let recv_buffer = &mut [0u8; 2048];
+                loop {
+                    let (n, source) = match udp_socket.recv_from(recv_buffer) {
+                        Ok(received) => received,
+                        Err(e) => {
+                            //Windows reports unreachable ports of previous sends here
+                            debug!("udp mux receive error: {}", e);
+                            continue;
+                        }
+                    };
+                    if n < 4 {
+                        continue;
+                    }
+                    let mut connection = [0u8; 4];
+                    connection.copy_from_slice(&recv_buffer[..4]);
+                    let mut handlers = handlers.lock().unwrap();
+                    if let Some(Some(handler)) = handlers.get_mut(&u32::from_be_bytes(connection)) {
+                        handler(&udp_socket, &recv_buffer[4..n], source);
+                    }
+                }
+            });
+        }
+    }
+
+    /// Reserves a connection id for a new peer, 0 is never picked since it addresses
+    /// a whole forwarding room
+    pub fn allocate(&self) -> u32 {
+        let mut handlers = self.handlers.lock().unwrap();
+        loop {
+            let connection = OsRng.next_u32();
+            if connection == ROOM_CONNECTION {
+                continue;
+            }
+            if let Entry::Vacant(entry) = handlers.entry(connection) {
+                entry.insert(None);
+                return connection;
+            }
+        }
+    }
+
+    /// Routes the packets of a connection to a handler
+    pub fn set_handler(&self, connection: u32, handler: Box<PacketHandler>) {
+        self.handlers.lock().unwrap().insert(connection, Some(handler));
+    }
+
+    /// Frees a connection id, its packets are dropped from now on
+    pub fn release(&self, connection: u32) {
+        self.handlers.lock().unwrap().remove(&connection);
+    }
+
+    /// Returns the candidates of the sockets, highest priority first
+    pub fn get_candidates(&self) -> &[Candidate] {
+        &self.candidates
+    }
+
+    /// Returns the sockets, one per address family
+    pub fn get_sockets(&self) -> &[UdpSocket] {
+        &self.sockets
+    }
+
+    /// Sends a packet to a connection of a remote mux
+    /// # Arguments
+    /// * `socket` - The index of the socket to send from
+    /// * `connection` - The connection id the remote peer picked
+    /// * `packet` - The packet
+    /// * `addr` - The remote address
+    pub fn send_to(&self, socket: usize, connection: u32, packet: &[u8], addr: SocketAddr) -> Result<usize, Error> {
+        let mut framed = BytesMut::with_capacity(packet.len() + 4);
+        framed.put_u32(connection);
+        framed.put(packet);
+        Self::send_framed(&self.sockets[socket], &framed, addr)
+    }
+
+    /// Sends a packet to a connection of a remote mux through the server relay
+    /// # Arguments
+    /// * `socket` - The index of the socket to send from
+    /// * `token` - The token the relay knows this peer by
+    /// * `connection` - The connection id the remote peer picked
+    /// * `packet` - The packet
+    /// * `relay` - The relay address
+    pub fn send_relayed(
+        &self,
+        socket: usize,
+        token: u64,
+        connection: u32,
+        packet: &[u8],
+        relay: SocketAddr,
+    ) -> Result<usize, Error> {
+        let mut framed = BytesMut::with_capacity(packet.len() + 12);
+        framed.put_u64(token);
+        framed.put_u32(connection);
+        framed.put(packet);
+        Self::send_framed(&self.sockets[socket], &framed, relay)
+    }
+
+    /// Tells the relay our address without sending a packet to the peer
+    pub fn refresh_relay(&self, socket: usize, token: u64, relay: SocketAddr) -> Result<usize, Error> {
+        Self::send_framed(&self.sockets[socket], &token.to_be_bytes(), relay)
+    }
+
+    /// Returns the index of the socket of the same family as the address
+    pub fn socket_for(&self, addr: &SocketAddr) -> Option<usize> {
+        self.sockets.iter().position(|socket| {
+            socket
+                .local_addr()
+                .is_ok_and(|local| local.is_ipv6() == addr.is_ipv6())
+        })
+    }
+
+    fn send_framed(socket: &UdpSocket, framed: &[u8], addr: SocketAddr) -> Result<usize, Error> {
+        socket.send_to(framed, addr)
+    }
+}
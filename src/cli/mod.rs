@@ -0,0 +1,207 @@
+// SPDX-FileCopyrightText: Copyright 2023 tSVoI
+// SPDX-License-Identifier: GPL-3.0-only
+
+use serde_json::json;
+use std::str::FromStr;
+
+const USAGE: &str = "Usage: tSVoI [options] <command> [arguments]
+
+Commands:
+  host      Host a room and talk in it (also 0)
+  join      Join the room of a server (also 1)
+  devices   Print the audio devices as json (also 3)
+  serve     Run a headless server that only does signaling and relaying
+  keygen    Print a new room key
+  help      Print the usage of a command
+
+Options of every command:
+  --config <path>                 Read the settings from this json file
+  --backend <backend list>        Audio backends to try in order, e.g. PulseAudio,ALSA
+  --failover                      Switch to the default device while the selected one is unplugged
+  --stun <host:port list>         STUN servers to try in order
+  --stun-timeout <milliseconds>   How long to wait for each STUN server
+  --lan                           Use the local interface addresses instead of STUN
+  --udp-ports <first>-<last>      Bind the audio sockets within this port range
+
+Run tSVoI help <command> for the arguments and options of a command.";
+
+const HOST_USAGE: &str = "Usage: tSVoI [options] host [<username> <capture device> <playback device>]
+
+Hosts a room and talks in it, clients join with the server_key of event 0.
+Devices are given by id, name or index, _ is the default device.
+
+Options:
+  --username <name>               Instead of the username argument
+  --capture <device>              Instead of the capture device argument
+  --playback <device>             Instead of the playback device argument
+SERVER_OPTIONS";
+
+const JOIN_USAGE: &str = "Usage: tSVoI [options] join [<username> <server address> <server key> <capture device> <playback device>]
+
+Joins the room of a server. The server address can be a comma separated list of the server_addresses
+of event 0, the first that answers is used.
+
+Options:
+  --username <name>               Instead of the username argument
+  --server <address list>         Instead of the server address argument
+  --key <key>                     Instead of the server key argument
+  --capture <device>              Instead of the capture device argument
+  --playback <device>             Instead of the playback device argument
+  --room <name>                   Join this room of the server, the key is then the key of the room
+  --invite <link>                 Join with an invite link instead of the server address and key,
+                                  a link given as an argument in their place works too";
+
+const SERVE_USAGE: &str = "Usage: tSVoI [options] serve
+
+Runs a server that only does signaling and relaying, it isn't in any room and doesn't open audio devices.
+Stdin only takes the control commands of a server (op_code 9 and 10) until it's closed.
+
+Options:
+SERVER_OPTIONS";
+
+const SERVER_OPTIONS: &str = "  --listen <ip:port list>         Listen on fixed addresses
+  --public-address <ip list>      Advertise these addresses, one per family, ip or ip:port
+  --no-relay                      Never relay audio through the server
+  --relay-bandwidth <kbit/s>      What each relayed peer may send, 0 for no limit
+  --heartbeat-interval <ms>       How often the clients are pinged, 0 to disable
+  --heartbeat-timeout <ms>        How long a silent client is kept
+  --max-peers <n>                 Most peers in each room, 0 for no limit
+  --require-invite                Turn away the clients that don't join with an invite token
+  --invite-secret <key>           Sign the invite tokens with this key from keygen
+  --room <name>[=<key>]           Host another room, repeatable
+  --sfu <room name>               Forward the voice of a room, repeatable
+  --sfu-max-speakers <n>          Most clients of a forwarding room heard at once, 0 for no limit";
+
+const DEVICES_USAGE: &str = "Usage: tSVoI [options] devices
+
+Prints the capture and playback devices of the audio backend as a single json line (event 5).";
+
+const KEYGEN_USAGE: &str = "Usage: tSVoI keygen
+
+Prints a new random key (event 21), to give to --room <name>=<key>, --invite-secret or the config file.";
+
+/// What the app was started to do
+#[derive(Clone, Copy, PartialEq)]
+pub enum Command {
+    Host,
+    Join,
+    Devices,
+    Serve,
+    Keygen,
+    Help,
+}
+impl Command {
+    /// Reads a command from its name, or from the number older front-ends pass
+    pub fn from_text(text: &str) -> Option<Self> {
+        match text {
+            "host" | "0" => Some(Command::Host),
+            "join" | "1" => Some(Command::Join),
+            "devices" | "3" => Some(Command::Devices),
+            "serve" => Some(Command::Serve),
+            "keygen" => Some(Command::Keygen),
+            "help" => Some(Command::Help),
+            _ => None,
+        }
+    }
+
+    /// Returns the number of positional arguments the command takes
+    pub fn max_arguments(&self) -> usize {
+        match self {
+            Command::Host => 3,
+            Command::Join => 5,
+            Command::Devices | Command::Serve | Command::Keygen => 0,
+            Command::Help => 1,
+        }
+    }
+
+    /// Returns the help text of the command
+    pub fn usage(&self) -> String {
+        match self {
+            Command::Host => HOST_USAGE.replace("SERVER_OPTIONS", SERVER_OPTIONS),
+            Command::Join => JOIN_USAGE.to_string(),
+            Command::Devices => DEVICES_USAGE.to_string(),
+            Command::Serve => SERVE_USAGE.replace("SERVER_OPTIONS", SERVER_OPTIONS),
+            Command::Keygen => KEYGEN_USAGE.to_string(),
+            Command::Help => USAGE.to_string(),
+        }
+    }
+}
+
+/// Prints the help text, of a command if one is named
+pub fn print_usage(command: Option<Command>) {
+    println!("{}", command.unwrap_or(Command::Help).usage());
+}
+
+/// Prints an invalid command line as event -1 and exits
+pub fn fail(message: &str) -> ! {
+    println!(
+        "{}",
+        json!({ "event_code": -1, "error": format!("{}, run tSVoI --help for the usage", message) })
+    );
+    std::process::exit(2);
+}
+
+/// Removes `name <value>` from the arguments and returns the value
+pub fn take_option(args: &mut Vec<String>, name: &str) -> Option<String> {
+    let pos = args.iter().position(|arg| arg == name)?;
+    if pos + 1 >= args.len() || args[pos + 1].starts_with("--") {
+        fail(&format!("Missing value for {}", name));
+    }
+    let value = args.remove(pos + 1);
+    args.remove(pos);
+    Some(value)
+}
+
+/// Removes `name <number>` from the arguments and returns the number
+pub fn take_number<T: FromStr>(args: &mut Vec<String>, name: &str) -> Option<T> {
+    let value = take_option(args, name)?;
+    match value.parse() {
+        Ok(number) => Some(number),
+        Err(_) => fail(&format!("Invalid value {} for {}", value, name)),
+    }
+}
+
+/// Removes `name` from the arguments and returns true if it was there
+pub fn take_flag(args: &mut Vec<String>, name: &str) -> bool {
+    match args.iter().position(|arg| arg == name) {
+        Some(pos) => {
+            args.remove(pos);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Fails on the options left once every known option is taken
+pub fn check_unknown_options(args: &[String]) {
+    if let Some(option) = args.iter().find(|arg| arg.starts_with("--")) {
+        fail(&format!("Unknown option {}", option));
+    }
+}
+
+/// Resolves the arguments of a command. The positional arguments fill, in order, the arguments
+/// that weren't given as options and the config file fills the rest.
+/// # Arguments
+/// * `arguments` - For each argument its name, the value of its option and the value of the config file
+/// * `positionals` - The positional arguments after the command
+/// # Returns
+/// * The value of each argument, fails if one is missing or there are too many positional arguments
+pub fn resolve_arguments(arguments: Vec<(&str, Option<String>, Option<String>)>, positionals: &[String]) -> Vec<String> {
+    let free = arguments.iter().filter(|(_, option, _)| option.is_none()).count();
+    if positionals.len() > free {
+        fail(&format!("Too many arguments, {} given where {} are expected", positionals.len(), free));
+    }
+    let mut positionals = positionals.iter();
+    arguments
+        .into_iter()
+        .map(|(name, option, fallback)| {
+            let value = match option {
+                Some(value) => Some(value),
+                None => positionals.next().cloned().or(fallback),
+            };
+            value.unwrap_or_else(|| {
+                fail(&format!("Missing {}, give it as an argument, an option or in the config file", name))
+            })
+        })
+        .collect()
+}
@@ -0,0 +1,330 @@
+// SPDX-FileCopyrightText: Copyright 2023 tSVoI
+// SPDX-License-Identifier: GPL-3.0-only
+
+use serde_json::{Map, Value};
+use std::env;
+use std::io::{Error, ErrorKind};
+use std::path::PathBuf;
+
+use crate::signaling::relay::MAX_RELAY_BANDWIDTH;
+use crate::signaling::CANDIDATE_SEPARATOR;
+
+/// Sample rates the opus encoder accepts
+const OPUS_SAMPLE_RATES: [u64; 5] = [8_000, 12_000, 16_000, 24_000, 48_000];
+
+/// The settings read from the config file, None for the settings it doesn't have.
+/// The command line flags override them.
+#[derive(Default)]
+pub struct Config {
+    //identity
+    pub username: Option<String>,
+    //devices
+    /// Comma separated, as given to --backend
+    pub backends: Option<String>,
+    pub capture: Option<String>,
+    pub playback: Option<String>,
+    pub failover: Option<bool>,
+    //audio
+    pub capture_channels: Option<u32>,
+    pub capture_sample_rate: Option<u32>,
+    pub playback_channels: Option<u32>,
+    pub playback_sample_rate: Option<u32>,
+    /// bits/s of the opus encoder
+    pub bitrate: Option<i32>,
+    /// Voice below this level, 0 to 100, isn't sent
+    pub threshold: Option<i8>,
+    //network
+    pub stun: Option<Vec<String>>,
+    pub stun_timeout_ms: Option<u64>,
+    pub lan: Option<bool>,
+    pub udp_ports: Option<(u16, u16)>,
+    pub server_address: Option<String>,
+    pub server_key: Option<String>,
+    pub room: Option<String>,
+    //server
+    /// Comma separated, as given to --listen
+    pub listen: Option<String>,
+    /// Comma separated, as given to --public-address
+    pub public_address: Option<String>,
+    pub relay: Option<bool>,
+    pub relay_bandwidth: Option<u32>,
+    pub heartbeat_interval_ms: Option<u64>,
+    pub heartbeat_timeout_ms: Option<u64>,
+    pub max_peers: Option<u16>,
+    pub require_invite: Option<bool>,
+    pub invite_secret: Option<String>,
+    pub sfu_max_speakers: Option<u16>,
+    /// The rooms added next to the default room, with their keys
+    pub rooms: Vec<(String, Option<String>)>,
+    /// The rooms whose voice the server forwards
+    pub forwarding: Vec<String>,
+}
+
+/// Returns where the config file is read from when --config isn't given,
+/// `tsvoi/config.json` in the config directory of the user
+pub fn default_path() -> Option<PathBuf> {
+    let dir = if cfg!(windows) {
+        PathBuf::from(env::var_os("APPDATA")?)
+    } else {
+        match env::var_os("XDG_CONFIG_HOME") {
+            Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+            _ => PathBuf::from(env::var_os("HOME")?).join(".config"),
+        }
+    };
+    Some(dir.join("tsvoi").join("config.json"))
+}
+
+/// Reads the config file
+/// # Arguments
+/// * `path` - The file given with --config, it must exist. None reads the file at the default
+///   path if there is one
+/// # Returns
+/// * The settings, an empty config if there is no file
+/// # Errors
+/// * The file can't be read, isn't json or has a setting that is unknown or out of range
+pub fn load(path: Option<&str>) -> Result<Config, Error> {
+    let path = match path {
+        Some(path) => PathBuf::from(path),
+        None => match default_path() {
+            Some(path) if path.is_file() => path,
+            _ => return Ok(Config::default()),
+        },
+    };
+    let text = std::fs::read_to_string(&path)
+        .map_err(|e| Error::new(e.kind(), format!("Can't read {}: {}", path.display(), e)))?;
+    let root: Value = serde_json::from_str(&text)
+        .map_err(|e| Error::new(ErrorKind::InvalidData, format!("{}: {}", path.display(), e)))?;
+    debug!("Reading the config file {}", path.display());
+    parse(&root).map_err(|e| Error::new(ErrorKind::InvalidData, format!("{}: {}", path.display(), e)))
+}
+
+/// Reads the settings of each section
+fn parse(root: &Value) -> Result<Config, Error> {
+    let root = Section::root(root)?;
+    root.check_keys(&["identity", "devices", "audio", "network", "server"])?;
+    let mut config = Config::default();
+
+    let identity = root.section("identity")?;
+    identity.check_keys(&["username"])?;
+    config.username = identity.string("username")?;
+
+    let devices = root.section("devices")?;
+    devices.check_keys(&["backends", "capture", "playback", "failover"])?;
+    config.backends = devices.list("backends")?.map(|backends| backends.join(","));
+    config.capture = devices.string("capture")?;
+    config.playback = devices.string("playback")?;
+    config.failover = devices.boolean("failover")?;
+
+    let audio = root.section("audio")?;
+    audio.check_keys(&[
+        "capture_channels",
+        "capture_sample_rate",
+        "playback_channels",
+        "playback_sample_rate",
+        "bitrate",
+        "threshold",
+    ])?;
+    config.capture_channels = audio.uint("capture_channels", 1, 2)?.map(|n| n as u32);
+    config.capture_sample_rate = audio.uint("capture_sample_rate", 8_000, 48_000)?.map(|n| n as u32);
+    if let Some(rate) = config.capture_sample_rate {
+        if !OPUS_SAMPLE_RATES.contains(&(rate as u64)) {
+            return Err(audio.invalid("capture_sample_rate", format!("must be one of {:?}", OPUS_SAMPLE_RATES)));
+        }
+    }
+    config.playback_channels = audio.uint("playback_channels", 1, 2)?.map(|n| n as u32);
+    config.playback_sample_rate = audio.uint("playback_sample_rate", 8_000, 384_000)?.map(|n| n as u32);
+    config.bitrate = audio.uint("bitrate", 500, 512_000)?.map(|n| n as i32);
+    config.threshold = audio.uint("threshold", 0, 100)?.map(|n| n as i8);
+
+    let network = root.section("network")?;
+    network.check_keys(&[
+        "stun",
+        "stun_timeout_ms",
+        "lan",
+        "udp_ports",
+        "server_address",
+        "server_key",
+        "room",
+    ])?;
+    config.stun = network.list("stun")?;
+    config.stun_timeout_ms = network.uint("stun_timeout_ms", 1, u32::MAX as u64)?;
+    config.lan = network.boolean("lan")?;
+    if let Some(ports) = network.string("udp_ports")? {
+        let range = parse_port_range(&ports)
+            .ok_or_else(|| network.invalid("udp_ports", "expected \"<first>-<last>\"".to_string()))?;
+        config.udp_ports = Some(range);
+    }
+    config.server_address = network.list("server_address")?.map(|addresses| addresses.join(","));
+    config.server_key = network.string("server_key")?;
+    config.room = network.string("room")?;
+
+    let server = root.section("server")?;
+    server.check_keys(&[
+        "listen",
+        "public_address",
+        "relay",
+        "relay_bandwidth",
+        "heartbeat_interval_ms",
+        "heartbeat_timeout_ms",
+        "max_peers",
+        "require_invite",
+        "invite_secret",
+        "sfu_max_speakers",
+        "rooms",
+    ])?;
+    config.listen = server.list("listen")?.map(|addresses| addresses.join(","));
+    config.public_address = server.list("public_address")?.map(|addresses| addresses.join(","));
+    config.relay = server.boolean("relay")?;
+    config.relay_bandwidth = server.uint("relay_bandwidth", 0, MAX_RELAY_BANDWIDTH as u64)?.map(|n| n as u32);
+    config.heartbeat_interval_ms = server.uint("heartbeat_interval_ms", 0, u32::MAX as u64)?;
+    config.heartbeat_timeout_ms = server.uint("heartbeat_timeout_ms", 1, u32::MAX as u64)?;
+    config.max_peers = server.uint("max_peers", 0, u16::MAX as u64)?.map(|n| n as u16);
+    config.require_invite = server.boolean("require_invite")?;
+    config.invite_secret = server.string("invite_secret")?;
+    config.sfu_max_speakers = server.uint("sfu_max_speakers", 0, u16::MAX as u64)?.map(|n| n as u16);
+    for (i, room) in server.array("rooms")?.iter().enumerate() {
+        let room = Section::new(format!("server.rooms[{}]", i), room)?;
+        room.check_keys(&["name", "key", "sfu"])?;
+        let name = room
+            .string("name")?
+            .ok_or_else(|| room.invalid("name", "is missing".to_string()))?;
+        if room.boolean("sfu")?.unwrap_or(false) {
+            config.forwarding.push(name.clone());
+        }
+        config.rooms.push((name, room.string("key")?));
+    }
+    Ok(config)
+}
+
+/// Reads a port range, `<first>-<last>`
+/// # Returns
+/// * The first and last port, None if the range is invalid or empty
+pub fn parse_port_range(text: &str) -> Option<(u16, u16)> {
+    let (first, last) = text.split_once('-')?;
+    let range = (first.trim().parse::<u16>().ok()?, last.trim().parse::<u16>().ok()?);
+    (range.0 != 0 && range.0 <= range.1).then_some(range)
+}
+
+/// An object of the config file, errors name the key with its section
+struct Section<'a> {
+    name: String,
+    values: Option<&'a Map<String, Value>>,
+}
+impl<'a> Section<'a> {
+    fn root(value: &'a Value) -> Result<Self, Error> {
+        match value.as_object() {
+            Some(values) => Ok(Section {
+                name: String::new(),
+                values: Some(values),
+            }),
+            None => Err(Error::new(ErrorKind::InvalidData, "the config must be a json object")),
+        }
+    }
+
+    fn new(name: String, value: &'a Value) -> Result<Self, Error> {
+        match value.as_object() {
+            Some(values) => Ok(Section {
+                name,
+                values: Some(values),
+            }),
+            None => Err(Error::new(ErrorKind::InvalidData, format!("{} must be an object", name))),
+        }
+    }
+
+    /// Returns a child object, a missing object has no settings
+    fn section(&self, key: &str) -> Result<Section<'a>, Error> {
+        match self.get(key) {
+            Some(value) => Section::new(self.path(key), value),
+            None => Ok(Section {
+                name: self.path(key),
+                values: None,
+            }),
+        }
+    }
+
+    fn path(&self, key: &str) -> String {
+        if self.name.is_empty() {
+            key.to_string()
+        } else {
+            format!("{}.{}", self.name, key)
+        }
+    }
+
+    fn get(&self, key: &str) -> Option<&'a Value> {
+        self.values?.get(key).filter(|value| !value.is_null())
+    }
+
+    fn invalid(&self, key: &str, reason: String) -> Error {
+        Error::new(ErrorKind::InvalidData, format!("{} {}", self.path(key), reason))
+    }
+
+    /// Fails on keys that aren't settings, most likely typos
+    fn check_keys(&self, known: &[&str]) -> Result<(), Error> {
+        let values = match self.values {
+            Some(values) => values,
+            None => return Ok(()),
+        };
+        match values.keys().find(|key| !known.contains(&key.as_str())) {
+            Some(key) => Err(self.invalid(key, format!("is unknown, expected one of {:?}", known))),
+            None => Ok(()),
+        }
+    }
+
+    fn string(&self, key: &str) -> Result<Option<String>, Error> {
+        match self.get(key) {
+            Some(value) => value
+                .as_str()
+                .map(|value| Some(value.to_string()))
+                .ok_or_else(|| self.invalid(key, "must be a string".to_string())),
+            None => Ok(None),
+        }
+    }
+
+    fn boolean(&self, key: &str) -> Result<Option<bool>, Error> {
+        match self.get(key) {
+            Some(value) => value
+                .as_bool()
+                .map(Some)
+                .ok_or_else(|| self.invalid(key, "must be true or false".to_string())),
+            None => Ok(None),
+        }
+    }
+
+    fn uint(&self, key: &str, min: u64, max: u64) -> Result<Option<u64>, Error> {
+        match self.get(key) {
+            Some(value) => match value.as_u64() {
+                Some(n) if (min..=max).contains(&n) => Ok(Some(n)),
+                _ => Err(self.invalid(key, format!("must be a whole number from {} to {}", min, max))),
+            },
+            None => Ok(None),
+        }
+    }
+
+    fn array(&self, key: &str) -> Result<Vec<&'a Value>, Error> {
+        match self.get(key) {
+            Some(value) => value
+                .as_array()
+                .map(|values| values.iter().collect())
+                .ok_or_else(|| self.invalid(key, "must be an array".to_string())),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// Reads an array of strings, or a single string with comma separated items
+    fn list(&self, key: &str) -> Result<Option<Vec<String>>, Error> {
+        let value = match self.get(key) {
+            Some(value) => value,
+            None => return Ok(None),
+        };
+        if let Some(text) = value.as_str() {
+            return Ok(Some(text.split(CANDIDATE_SEPARATOR).map(|item| item.trim().to_string()).collect()));
+        }
+        let items: Option<Vec<String>> = value
+            .as_array()
+            .map(|items| items.iter().map(|item| item.as_str().map(|s| s.to_string())).collect())
+            .unwrap_or(None);
+        items
+            .map(Some)
+            .ok_or_else(|| self.invalid(key, "must be a string or an array of strings".to_string()))
+    }
+}
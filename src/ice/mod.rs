@@ -0,0 +1,321 @@
+// SPDX-FileCopyrightText: Copyright 2023 tSVoI
+// SPDX-License-Identifier: GPL-3.0-only
+
+use bytes::{BufMut, Bytes, BytesMut};
+use flume::Receiver;
+use std::collections::HashMap;
+use std::fmt;
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::aes::AES;
+use crate::signaling;
+
+/// Packet types, the type is the first byte of every decrypted udp packet
+pub const VOICE: u8 = 0;
+pub const BINDING_REQUEST: u8 = 1;
+pub const BINDING_RESPONSE: u8 = 2;
+pub const KEEPALIVE: u8 = 3;
+pub const KEEPALIVE_ACK: u8 = 4;
+
+/// Time between two binding requests, requests go round robin over the pairs
+const CHECK_PACING: Duration = Duration::from_millis(20);
+/// Time to wait for a higher priority pair once a pair works
+const NOMINATION_DELAY: Duration = Duration::from_millis(300);
+/// Time to wait for any pair to work
+const CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+static TRANSACTION_COUNTER: AtomicU64 = AtomicU64::new(1);
+
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum CandidateKind {
+    /// The address of a local interface
+    Host,
+    /// The address a STUN server sees, the public side of a NAT
+    ServerReflexive,
+    /// An address on a relay that forwards to us
+    Relayed,
+}
+impl CandidateKind {
+    pub fn to_text(self) -> &'static str {
+        match self {
+            CandidateKind::Host => "host",
+            CandidateKind::ServerReflexive => "srflx",
+            CandidateKind::Relayed => "relay",
+        }
+    }
+
+    pub fn from_text(text: &str) -> Option<Self> {
+        match text {
+            "host" => Some(CandidateKind::Host),
+            "srflx" => Some(CandidateKind::ServerReflexive),
+            "relay" => Some(CandidateKind::Relayed),
+            _ => None,
+        }
+    }
+
+    /// Direct paths are preferred, relays are the last resort
+    fn type_preference(self) -> u32 {
+        match self {
+            CandidateKind::Host => 126,
+            CandidateKind::ServerReflexive => 100,
+            CandidateKind::Relayed => 0,
+        }
+    }
+}
+
+/// An address a peer can be reached at
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub struct Candidate {
+    pub kind: CandidateKind,
+    pub addr: SocketAddr,
+}
+impl Candidate {
+    pub fn new(kind: CandidateKind, addr: SocketAddr) -> Self {
+        Candidate { kind, addr }
+    }
+
+    /// Priority as in ICE, by type first and ipv6 over ipv4 second
+    pub fn priority(&self) -> u32 {
+        let local_preference = if self.addr.is_ipv6() { 65535 } else { 32767 };
+        (self.kind.type_preference() << 24) + (local_preference << 8) + 255
+    }
+
+    /// Parses `<type> <ip:port>`
+    pub fn from_text(text: &str) -> Option<Self> {
+        let (kind, addr) = text.trim().split_once(' ')?;
+        Some(Candidate::new(
+            CandidateKind::from_text(kind)?,
+            addr.parse().ok()?,
+        ))
+    }
+}
+impl fmt::Display for Candidate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}", self.kind.to_text(), self.addr)
+    }
+}
+
+/// Parses the candidate list of an announce or acknowledge message, invalid candidates are skipped
+pub fn parse_candidates(text: &str) -> Vec<Candidate> {
+    signaling::split_candidates(text)
+        .iter()
+        .filter_map(|candidate| {
+            let parsed = Candidate::from_text(candidate);
+            if parsed.is_none() {
+                warn!("Ignoring invalid candidate {}", candidate);
+            }
+            parsed
+        })
+        .collect()
+}
+
+/// Serializes candidates for an announce or acknowledge message
+pub fn candidates_to_text(candidates: &[Candidate]) -> String {
+    let candidates: Vec<String> = candidates.iter().map(|c| c.to_string()).collect();
+    signaling::join_candidates(&candidates)
+}
+
+/// Gathers the host and server reflexive candidates of each socket, highest priority first.
+/// Every interface address is a host candidate, so peers on a network without a route still
+/// find each other. STUN is queried from the sockets themselves so the reflexive ports are the
+/// ones peers must use. Relayed candidates aren't gathered, the server offers them with each
+/// punch round.
+pub fn gather(sockets: &[UdpSocket]) -> Vec<Candidate> {
+    let mut candidates = Vec::new();
+    for socket in sockets {
+        let local = match socket.local_addr() {
+            Ok(local) => local,
+            Err(_) => continue,
+        };
+        let interfaces = signaling::get_interface_addresses(local.is_ipv6());
+        if interfaces.is_empty() {
+            match signaling::get_local_address(socket, local.is_ipv6()) {
+                Ok(host) => candidates.push(Candidate::new(CandidateKind::Host, host)),
+                Err(e) => debug!("No host candidate for {}: {}", local, e),
+            }
+        }
+        for ip in interfaces {
+            candidates.push(Candidate::new(CandidateKind::Host, SocketAddr::new(ip, local.port())));
+        }
+        if let Some(mapped) = signaling::get_mapped_address(socket, local.is_ipv6()) {
+            let reflexive = Candidate::new(CandidateKind::ServerReflexive, mapped);
+            //Without a NAT the reflexive address is the host address
+            if !candidates.iter().any(|c| c.addr == mapped) {
+                candidates.push(reflexive);
+            }
+        }
+    }
+    candidates.sort_by_key(|c| std::cmp::Reverse(c.priority()));
+    candidates
+}
+
+/// A local socket and a remote candidate of the same family
+#[derive(Clone, Copy, Debug)]
+pub struct CandidatePair {
+    /// Index of the local socket
+    pub socket: usize,
+    pub remote: Candidate,
+    pub priority: u64,
+}
+
+/// Pairs every remote candidate with the local socket of its family, highest priority first.
+/// The priority formula is symmetric so both peers order the pairs the same way.
+/// # Arguments
+/// * `sockets` - The local sockets, one per family
+/// * `local` - The local candidates
+/// * `remote` - The candidates of the peer
+pub fn pair(sockets: &[UdpSocket], local: &[Candidate], remote: &[Candidate]) -> Vec<CandidatePair> {
+    let mut pairs = Vec::new();
+    for remote_candidate in remote {
+        let socket = sockets.iter().position(|socket| {
+            socket
+                .local_addr()
+                .is_ok_and(|addr| addr.is_ipv6() == remote_candidate.addr.is_ipv6())
+        });
+        let socket = match socket {
+            Some(socket) => socket,
+            None => continue,
+        };
+        //Reflexive candidates share the socket of the host candidate, which has the highest priority
+        let local_priority = local
+            .iter()
+            .filter(|c| c.addr.is_ipv6() == remote_candidate.addr.is_ipv6())
+            .map(|c| c.priority())
+            .max()
+            .unwrap_or(0) as u64;
+        let remote_priority = remote_candidate.priority() as u64;
+        pairs.push(CandidatePair {
+            socket,
+            remote: *remote_candidate,
+            priority: (local_priority.min(remote_priority) << 32) + 2 * local_priority.max(remote_priority),
+        });
+    }
+    pairs.sort_by_key(|pair| std::cmp::Reverse(pair.priority));
+    pairs
+}
+
+/// Builds an encrypted binding request
+/// # Returns
+/// * The transaction id and the packet
+pub fn binding_request(aes: &AES) -> (u64, Bytes) {
+    let transaction = TRANSACTION_COUNTER.fetch_add(1, Ordering::Relaxed);
+    let mut request = BytesMut::with_capacity(9);
+    request.put_u8(BINDING_REQUEST);
+    request.put_u64(transaction);
+    (transaction, aes.encrypt(request).unwrap())
+}
+
+/// Builds an encrypted answer to a binding request, it echoes the transaction id
+/// and tells the peer the address its request came from
+/// # Arguments
+/// * `request` - The decrypted binding request
+/// * `source` - The address the request came from
+pub fn binding_response(aes: &AES, request: &[u8], source: SocketAddr) -> Option<Bytes> {
+    if request.len() != 9 {
+        return None;
+    }
+    let mut response = BytesMut::with_capacity(64);
+    response.put_u8(BINDING_RESPONSE);
+    response.put(&request[1..9]);
+    response.put(source.to_string().as_bytes());
+    aes.encrypt(response).ok()
+}
+
+/// Reads a decrypted binding response
+/// # Returns
+/// * The transaction id and the address the peer saw our request come from
+pub fn parse_binding_response(response: &[u8]) -> Option<(u64, SocketAddr)> {
+    if response.len() < 9 {
+        return None;
+    }
+    let mut transaction = [0u8; 8];
+    transaction.copy_from_slice(&response[1..9]);
+    let mapped = std::str::from_utf8(&response[9..]).ok()?.parse().ok()?;
+    Some((u64::from_be_bytes(transaction), mapped))
+}
+
+/// The pair a peer talks through
+#[derive(Clone, Copy, Debug)]
+pub struct SelectedPath {
+    pub pair: CandidatePair,
+    /// Our address as seen by the peer
+    pub mapped: SocketAddr,
+    pub rtt: Duration,
+}
+
+/// Runs connectivity checks on every pair and returns the highest priority pair that works.
+/// Once a direct pair works the checks go on for a moment in case a better pair answers too,
+/// a relayed pair only wins if no direct pair answers before the timeout.
+/// # Arguments
+/// * `pairs` - The pairs to check, highest priority first
+/// * `responses` - The binding responses read by the socket receivers
+/// * `aes` - The cipher that authenticates the requests
+/// * `send` - Sends a request on a pair
+pub fn check<F>(
+    pairs: &[CandidatePair],
+    responses: &Receiver<(u64, SocketAddr)>,
+    aes: &AES,
+    send: F,
+) -> Option<SelectedPath>
+where
+    F: Fn(&CandidatePair, &[u8]),
+{
+    if pairs.is_empty() {
+        return None;
+    }
+    let mut transactions: HashMap<u64, (usize, Instant)> = HashMap::new();
+    let mut valid: Vec<SelectedPath> = Vec::new();
+    let mut valid_pairs: Vec<usize> = Vec::new();
+    let deadline = Instant::now() + CHECK_TIMEOUT;
+    let mut nomination_deadline: Option<Instant> = None;
+    let mut next_pair = 0;
+
+    loop {
+        let now = Instant::now();
+        if now >= deadline || nomination_deadline.is_some_and(|d| now >= d) {
+            break;
+        }
+        //The best pair works, nothing can beat it
+        if valid_pairs.contains(&0) {
+            break;
+        }
+
+        //Skip the pairs that already work
+        if valid_pairs.len() < pairs.len() {
+            while valid_pairs.contains(&next_pair) {
+                next_pair = (next_pair + 1) % pairs.len();
+            }
+            let pair = &pairs[next_pair];
+            let (transaction, request) = binding_request(aes);
+            transactions.insert(transaction, (next_pair, now));
+            debug!("Checking {}", pair.remote);
+            send(pair, &request);
+            next_pair = (next_pair + 1) % pairs.len();
+        }
+
+        let wait_until = now + CHECK_PACING;
+        while let Ok((transaction, mapped)) = responses.recv_deadline(wait_until) {
+            let (index, sent_at) = match transactions.get(&transaction) {
+                Some(sent) => *sent,
+                None => continue,
+            };
+            if valid_pairs.contains(&index) {
+                continue;
+            }
+            debug!("Pair {} works", pairs[index].remote);
+            valid_pairs.push(index);
+            valid.push(SelectedPath {
+                pair: pairs[index],
+                mapped,
+                rtt: sent_at.elapsed(),
+            });
+            //The relay answers at once, the direct pairs get the whole timeout to punch through
+            if pairs[index].remote.kind != CandidateKind::Relayed {
+                nomination_deadline.get_or_insert(Instant::now() + NOMINATION_DELAY);
+            }
+        }
+    }
+    valid.into_iter().max_by_key(|path| path.pair.priority)
+}
@@ -2,18 +2,37 @@
 extern crate log;
 // SPDX-FileCopyrightText: Copyright 2023 tSVoI
 // SPDX-License-Identifier: GPL-3.0-only
-use serde_json::Value;
+use flume::Receiver;
+use serde_json::{json, Value};
+use std::collections::HashMap;
 use std::env;
+use std::sync::{Arc, Mutex};
 use std::thread;
 
 mod aes;
 mod audio;
 mod audio_peer;
+mod cli;
+mod config;
+mod ice;
+mod roster;
 mod signaling;
 use audio::capture::AudioCapture;
-use audio::Audio;
+use audio::device::DeviceSelection;
+use audio::watcher::{DeviceEvent, DeviceWatcher, FailoverPolicy};
+use audio::{Audio, DeviceError, DeviceKind};
+use audio_peer::{mux, AudioPeer};
+use cli::{take_flag, take_number, take_option, Command};
 use signaling::client::SignalingClient;
+use signaling::invite::{self, InviteLink, INVITE_SCHEME};
 use signaling::server::SignalingServer;
+use signaling::PeerId;
+
+/// How often the device lists are polled for hot-plugged devices
+const DEVICE_POLL_INTERVAL: std::time::Duration = std::time::Duration::from_secs(2);
+
+/// A command the stdin thread passes to the audio loop, the fields depend on the op_code
+type StdinCommand = (u8, u8, u8, u16, u16, Option<String>);
 
 #[macro_export]
 macro_rules! spawn_thread {
@@ -25,11 +44,510 @@ macro_rules! spawn_thread {
     };
 }
 
+/// The settings of a server, shared by the server that talks in its room and the headless server
+struct ServerOptions {
+    /// A comma separated list of addresses to listen on, None to use the addresses found with STUN
+    listen: Option<String>,
+    /// A comma separated list of addresses to advertise instead of the addresses found
+    public: Option<String>,
+    relay: bool,
+    relay_bandwidth: u32,
+    heartbeat_interval: std::time::Duration,
+    heartbeat_timeout: std::time::Duration,
+    max_peers: u16,
+    /// The rooms added next to the default room, with their keys
+    rooms: Vec<(String, Option<String>)>,
+    /// The rooms whose voice the server forwards
+    forwarding: Vec<String>,
+    /// Most senders forwarded at the same time in a forwarding room, 0 for no limit
+    max_speakers: u16,
+    /// True if clients need an invite link with a token to join
+    require_invite: bool,
+    /// The key invite tokens are signed with, None for a random key
+    invite_secret: Option<String>,
+}
+
+/// Creates a server with the options and prints its addresses and keys (event 0)
+/// # Arguments
+/// * `username` - The username of the server in its room, None for a headless server
+/// * `options` - The server settings
+/// # Returns
+/// * The server, None if it couldn't start (event -1)
+fn start_server(username: Option<String>, options: &ServerOptions) -> Option<SignalingServer> {
+    let mut server = match SignalingServer::new(username, options.listen.as_deref(), options.public.as_deref()) {
+        Ok(server) => server,
+        Err(e) => {
+            println!("{{ \"event_code\": -1, \"error\": \"Failed to start server: {}\" }}", e);
+            return None;
+        }
+    };
+    server.set_relay_enabled(options.relay);
+    server.set_relay_bandwidth(options.relay_bandwidth);
+    server.set_heartbeat(options.heartbeat_interval, options.heartbeat_timeout);
+    server.set_max_peers(options.max_peers);
+    for (name, key) in options.rooms.iter() {
+        if let Err(e) = server.add_room(name, key.as_deref()) {
+            println!("{}", json!({ "event_code": -1, "error": format!("Failed to add room: {}", e) }));
+            return None;
+        }
+    }
+    for name in options.forwarding.iter() {
+        if let Err(e) = server.set_forwarding(name) {
+            println!("{}", json!({ "event_code": -1, "error": format!("Failed to forward room: {}", e) }));
+            return None;
+        }
+    }
+    server.set_max_speakers(options.max_speakers);
+    server.set_invite_required(options.require_invite);
+    if let Some(secret) = &options.invite_secret {
+        if let Err(e) = server.set_invite_secret(secret) {
+            println!("{}", json!({ "event_code": -1, "error": format!("Failed to start server: {}", e) }));
+            return None;
+        }
+    }
+    let rooms: Vec<Value> = server
+        .get_rooms()
+        .into_iter()
+        .map(|(name, key)| json!({ "name": name, "key": key, "invite": server.get_invite_link(&name, None) }))
+        .collect();
+    println!(
+        "{{ \"event_code\": 0, \"server_address\": \"{}\", \"server_addresses\": {}, \"server_key\": \"{}\", \"invite\": {}, \"rooms\": {} }}",
+        server.get_listen_address(),
+        json!(server.get_listen_addresses()),
+        server.get_cipher_key(),
+        json!(server.get_invite_link("", None)),
+        json!(rooms)
+    );
+    Some(server)
+}
+
+/// Runs a server that only does signaling and relaying, without audio devices, until it's killed.
+/// Stdin only takes the control commands of a server (op_code 9 and 10), until it's closed.
+/// # Arguments
+/// * `options` - The server settings
+/// * `playback` - The format of the server's audio peers, the server never plays what it receives
+fn serve(options: &ServerOptions, playback: DeviceSelection) {
+    let server = match start_server(None, options) {
+        Some(server) => server,
+        None => return,
+    };
+    server.run(playback);
+    spawn_thread!("nat type detection", signaling::print_nat_types);
+    for line in std::io::stdin().lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(_) => break,
+        };
+        match serde_json::from_str::<Value>(&line) {
+            Ok(parsed) if parsed["op_code"].as_u64() == Some(9) => create_invite(&server, &parsed),
+            Ok(parsed) if parsed["op_code"].as_u64() == Some(10) => revoke_invites(&server, &parsed),
+            Ok(_) => {}
+            Err(_) => println!("{{ \"event_code\": -1, \"error\": \"Failed to parse stdin\" }}"),
+        }
+    }
+    loop {
+        thread::park();
+    }
+}
+
+/// Revokes an invite token or every token and prints it (event 24), answer to op_code 10
+/// # Arguments
+/// * `server` - The server that handed out the tokens
+/// * `parsed` - `{ "invite": "<link or token>" }` or `{ "all": true }`
+fn revoke_invites(server: &SignalingServer, parsed: &Value) {
+    if parsed["all"].as_bool().unwrap_or(false) {
+        server.revoke_all_invites();
+        println!("{{ \"event_code\": 24, \"all\": true }}");
+        return;
+    }
+    let revoked = match parsed["invite"].as_str() {
+        Some(invite) => server.revoke_invite(invite),
+        None => Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "No invite given")),
+    };
+    match revoked {
+        Ok(_) => println!("{{ \"event_code\": 24, \"all\": false }}"),
+        Err(e) => println!("{}", json!({ "event_code": -1, "error": format!("Failed to revoke invite: {}", e) })),
+    }
+}
+
+/// Hands out an invite link with a token and prints it (event 22), answer to op_code 9
+/// # Arguments
+/// * `server` - The server the link joins
+/// * `parsed` - `{ "room": "<name>", "expires_in": <seconds>, "single_use": <bool> }`, every field optional
+fn create_invite(server: &SignalingServer, parsed: &Value) {
+    let room = parsed["room"].as_str().unwrap_or_default();
+    let lifetime = parsed["expires_in"]
+        .as_u64()
+        .filter(|seconds| *seconds != 0)
+        .map(std::time::Duration::from_secs);
+    let single_use = parsed["single_use"].as_bool().unwrap_or(false);
+    match server.create_invite(room, lifetime, single_use) {
+        Ok((link, expires)) => println!(
+            "{}",
+            json!({
+                "event_code": 22,
+                "room": room,
+                "invite": link,
+                "expires_at": expires.map(invite::to_unix),
+                "single_use": single_use,
+            })
+        ),
+        Err(e) => println!("{}", json!({ "event_code": -1, "error": format!("Failed to create invite: {}", e) })),
+    }
+}
+
+/// Re-creates the audio context with a new backend list and reports the selected backend
+fn change_backends(backends: &str) {
+    let backends = match Audio::backends_from_text(backends) {
+        Ok(backends) => backends,
+        Err(e) => {
+            println!("{}", json!({ "event_code": -1, "error": e }));
+            return;
+        }
+    };
+    match Audio::set_backends(backends) {
+        Ok(backend) => {
+            println!(
+                "{{ \"event_code\": 4, \"backend\": \"{}\" }}",
+                Audio::backend_to_text(backend)
+            );
+        }
+        Err(e) => {
+            println!("{{ \"event_code\": -1, \"error\": \"Failed to change audio backend: {}\" }}", e);
+        }
+    }
+}
+
+/// Mutes or deafens ourselves, answer to op_code 6 and 7
+/// # Arguments
+/// * `op_code` - 6 to change the mute state, 7 to change the deafen state
+/// * `enabled` - The new state
+fn set_self_presence(op_code: u8, enabled: bool) {
+    let flag = if op_code == 6 { roster::MUTED } else { roster::DEAFENED };
+    let flags = roster::get_self_flags();
+    let flags = if enabled { flags | flag } else { flags & !flag };
+    roster::set_presence(roster::get_self_id(), flags);
+}
+
+/// The devices and the peers the stdin commands and the device failover act on,
+/// the same for the server in its room and for a client
+struct AudioSession {
+    capture: AudioCapture,
+    audio_peers: Arc<Mutex<HashMap<PeerId, AudioPeer>>>,
+    /// The playback device new peers are played on
+    playback: Arc<Mutex<DeviceSelection>>,
+    policy: FailoverPolicy,
+    failover: bool,
+}
+impl AudioSession {
+    /// Creates a new AudioSession, the devices in use are the ones failover brings back
+    /// # Arguments
+    /// * `capture` - Our capture device
+    /// * `audio_peers` - The audio peers of the server or the client
+    /// * `playback` - The playback device of the server or the client
+    /// * `failover` - True to switch back to the preferred devices when they return
+    fn new(
+        capture: AudioCapture,
+        audio_peers: Arc<Mutex<HashMap<PeerId, AudioPeer>>>,
+        playback: Arc<Mutex<DeviceSelection>>,
+        failover: bool,
+    ) -> Self {
+        let mut policy = FailoverPolicy::new();
+        if failover {
+            policy.set_preferred(&capture.get_selection().device, DeviceKind::Capture);
+            policy.set_preferred(&playback.lock().unwrap().device, DeviceKind::Playback);
+        }
+        AudioSession {
+            capture,
+            audio_peers,
+            playback,
+            policy,
+            failover,
+        }
+    }
+
+    /// Handles the stdin command and the device event that arrived, without waiting for them
+    /// # Arguments
+    /// * `stdin_rx` - The commands of the stdin thread
+    /// * `device_rx` - The events of the device watcher
+    /// * `send_presence` - Tells the room our mute and deafen state
+    fn poll<F>(&mut self, stdin_rx: &Receiver<StdinCommand>, device_rx: &Receiver<DeviceEvent>, send_presence: F)
+    where
+        F: Fn(),
+    {
+        if let Ok(data) = stdin_rx.try_recv() {
+            match data.0 {
+                0 => {
+                    let selection = DeviceSelection::new(&data.5.unwrap(), data.1 as u32, data.3 as u32);
+                    match self.capture.change_device(selection.clone()) {
+                        Ok(_) => {
+                            Audio::print_device_changed(DeviceKind::Capture, &selection);
+                            //Failover now brings back the device the user just picked
+                            if self.failover {
+                                self.policy.set_preferred(&selection.device, DeviceKind::Capture);
+                            }
+                        }
+                        Err(e) => Audio::print_device_error(&e),
+                    }
+                }
+                1 => {
+                    let selection = DeviceSelection::new(&data.5.unwrap(), data.1 as u32, data.3 as u32);
+                    match self.change_playback(selection.clone()) {
+                        Ok(_) => {
+                            Audio::print_device_changed(DeviceKind::Playback, &selection);
+                            if self.failover {
+                                self.policy.set_preferred(&selection.device, DeviceKind::Playback);
+                            }
+                        }
+                        Err(e) => Audio::print_device_error(&e),
+                    }
+                }
+                2 => {
+                    self.change_peer_volume(data.3, data.2);
+                }
+                3 => {
+                    self.capture.set_encoder_bitrate(data.3 as i32);
+                }
+                4 => {
+                    change_backends(&data.5.unwrap());
+                }
+                6 | 7 => {
+                    set_self_presence(data.0, data.1 != 0);
+                    send_presence();
+                }
+                _ => {}
+            }
+        }
+        if let Ok(event) = device_rx.try_recv() {
+            if let Some((kind, device)) = self.policy.on_event(&event) {
+                let switched = match kind {
+                    DeviceKind::Capture => {
+                        let current = self.capture.get_selection();
+                        let selection = DeviceSelection::new(&device, current.channels, current.sample_rate);
+                        self.capture.change_device(selection)
+                    }
+                    DeviceKind::Playback => {
+                        let current = self.playback.lock().unwrap().clone();
+                        let selection = DeviceSelection::new(&device, current.channels, current.sample_rate);
+                        self.change_playback(selection)
+                    }
+                };
+                match switched {
+                    Ok(_) => println!(
+                        "{{ \"event_code\": 8, \"kind\": \"{}\", \"device\": \"{}\" }}",
+                        kind.to_text(),
+                        device
+                    ),
+                    Err(e) => Audio::print_device_error(&e),
+                }
+            }
+        }
+    }
+
+    /// Switches the playback device of every peer and of the peers that join later
+    /// # Arguments
+    /// * `selection` - The device, channels and sample rate to switch to
+    fn change_playback(&self, selection: DeviceSelection) -> Result<(), DeviceError> {
+        //Fail before touching the peers if the device doesn't exist
+        Audio::get_device_id(&selection.device, DeviceKind::Playback)?;
+        *self.playback.lock().unwrap() = selection.clone();
+        let peers = self.audio_peers.lock().unwrap();
+        for peer in peers.values() {
+            peer.change_device(selection.clone())?;
+        }
+        Ok(())
+    }
+
+    fn change_peer_volume(&self, peer_id: PeerId, volume: u8) {
+        let peers = self.audio_peers.lock().unwrap();
+        match peers.get(&peer_id) {
+            Some(peer) => peer.change_volume(volume),
+            None => error!("Peer {} not found", peer_id),
+        }
+    }
+}
+
 fn main() {
     env_logger::init();
-    let args: Vec<String> = env::args().collect::<Vec<String>>()[1..].to_vec();
+    let mut args: Vec<String> = env::args().collect::<Vec<String>>()[1..].to_vec();
+    //--help, -h or help [<command>]: print the usage instead of running
+    let help = take_flag(&mut args, "--help") || take_flag(&mut args, "-h");
+    if help || args.first().is_some_and(|arg| arg == "help") {
+        let named = args.iter().filter(|arg| *arg != "help").find_map(|arg| Command::from_text(arg));
+        cli::print_usage(named);
+        return;
+    }
+    //--config <path>: the config file, read from the config directory of the user if omitted.
+    //Every flag below overrides the setting of the file
+    let config = match config::load(take_option(&mut args, "--config").as_deref()) {
+        Ok(config) => config,
+        Err(e) => {
+            println!("{}", json!({ "event_code": -1, "error": format!("Invalid config file: {}", e) }));
+            return;
+        }
+    };
+    //--backend <comma separated backend list>
+    if let Some(backends) = take_option(&mut args, "--backend").or(config.backends.clone()) {
+        let backends = match Audio::backends_from_text(&backends) {
+            Ok(backends) => backends,
+            Err(e) => {
+                println!("{}", json!({ "event_code": -1, "error": e }));
+                return;
+            }
+        };
+        if let Err(e) = Audio::set_backends(backends) {
+            println!("{{ \"event_code\": -1, \"error\": \"Failed to create audio context: {}\" }}", e);
+            return;
+        }
+    }
+    //--failover: switch to the default device while the selected one is unplugged
+    let failover = take_flag(&mut args, "--failover") || config.failover.unwrap_or(false);
+    //--stun <comma separated host:port list> --stun-timeout <milliseconds>
+    let stun_servers = take_option(&mut args, "--stun")
+        .map(|servers| servers.split(',').map(|s| s.trim().to_string()).collect())
+        .or(config.stun.clone())
+        .unwrap_or_default();
+    let stun_timeout = take_number(&mut args, "--stun-timeout")
+        .or(config.stun_timeout_ms)
+        .map(std::time::Duration::from_millis)
+        .unwrap_or(signaling::DEFAULT_STUN_TIMEOUT);
+    signaling::set_stun_servers(stun_servers, stun_timeout);
+    //--lan: use local interface addresses instead of STUN
+    signaling::set_lan_mode(take_flag(&mut args, "--lan") || config.lan.unwrap_or(false));
+    //--relay-bandwidth <kbit/s>: what each relayed peer may send through the server, 0 for no limit
+    let relay_bandwidth = take_number(&mut args, "--relay-bandwidth")
+        .or(config.relay_bandwidth)
+        .unwrap_or(signaling::relay::DEFAULT_RELAY_BANDWIDTH);
+    if relay_bandwidth > signaling::relay::MAX_RELAY_BANDWIDTH {
+        cli::fail(&format!("--relay-bandwidth can't be above {}", signaling::relay::MAX_RELAY_BANDWIDTH));
+    }
+    //--heartbeat-interval <milliseconds> --heartbeat-timeout <milliseconds>: how often the server pings
+    //its clients and how long a silent client is kept, an interval of 0 disables the heartbeats
+    let heartbeat_interval = take_number(&mut args, "--heartbeat-interval")
+        .or(config.heartbeat_interval_ms)
+        .map(std::time::Duration::from_millis)
+        .unwrap_or(signaling::server::DEFAULT_HEARTBEAT_INTERVAL);
+    let heartbeat_timeout = take_number(&mut args, "--heartbeat-timeout")
+        .or(config.heartbeat_timeout_ms)
+        .map(std::time::Duration::from_millis)
+        .unwrap_or(signaling::server::DEFAULT_HEARTBEAT_TIMEOUT);
+    if !heartbeat_interval.is_zero() && heartbeat_timeout <= heartbeat_interval {
+        println!("{{ \"event_code\": -1, \"error\": \"The heartbeat timeout must be longer than the interval\" }}");
+        return;
+    }
+    //--require-invite: turn away the clients that don't join with an invite link handed out by op_code 9
+    let require_invite = take_flag(&mut args, "--require-invite") || config.require_invite.unwrap_or(false);
+    //--invite-secret <key>: sign the invite tokens with this key (from keygen) so they outlive a restart
+    let invite_secret = take_option(&mut args, "--invite-secret").or(config.invite_secret.clone());
+    //--no-relay: leave the peers that can't punch through without audio instead of relaying them
+    let relay = !take_flag(&mut args, "--no-relay") && config.relay.unwrap_or(true);
+    //--listen <comma separated ip:port list>: fixed addresses for the server to listen on
+    let listen = take_option(&mut args, "--listen").or(config.listen.clone());
+    //--public-address <comma separated ip or ip:port list>: what clients connect to, for a server behind a NAT
+    let public = take_option(&mut args, "--public-address").or(config.public_address.clone());
+    //--udp-ports <first>-<last>: the ports the audio sockets bind to, so a firewall can let them through
+    if let Some(ports) = take_option(&mut args, "--udp-ports") {
+        match config::parse_port_range(&ports) {
+            Some(range) => mux::set_port_range(Some(range)),
+            None => {
+                println!("{{ \"event_code\": -1, \"error\": \"Invalid udp port range {}, expected <first>-<last>\" }}", ports);
+                return;
+            }
+        }
+    } else if config.udp_ports.is_some() {
+        mux::set_port_range(config.udp_ports);
+    }
+    //--max-peers <n>: most peers in the room, the server included, 0 for no limit
+    let max_peers = take_number(&mut args, "--max-peers")
+        .or(config.max_peers)
+        .unwrap_or(0);
+    //--room <name>[=<key>], repeatable: the server hosts another room with its own key, generated if
+    //omitted. The client joins the named room instead of the room of the server.
+    let mut rooms = Vec::new();
+    while let Some(room) = take_option(&mut args, "--room") {
+        rooms.push(match room.split_once('=') {
+            Some((name, key)) => (name.to_string(), Some(key.to_string())),
+            None => (room, None),
+        });
+    }
+    //The client joins the room of the flag, the server hosts the rooms of the flags instead of the file's
+    let client_room = rooms.last().map(|(name, _)| name.clone()).or(config.room.clone()).unwrap_or_default();
+    if rooms.is_empty() {
+        rooms = config.rooms.clone();
+    }
+    //--sfu <room name>, repeatable: the server forwards the voice of the room, each client sends it
+    //once instead of once to every peer. "" is the default room of a headless server
+    let mut forwarding = Vec::new();
+    while let Some(room) = take_option(&mut args, "--sfu") {
+        forwarding.push(room);
+    }
+    if forwarding.is_empty() {
+        forwarding = config.forwarding.clone();
+    }
+    //--sfu-max-speakers <n>: most clients of a forwarding room heard at the same time, 0 for no limit
+    let max_speakers = take_number(&mut args, "--sfu-max-speakers")
+        .or(config.sfu_max_speakers)
+        .unwrap_or(0);
+    //The formats the devices are opened with and the encoder settings, only set by the config file
+    let capture_channels = config.capture_channels.unwrap_or(1);
+    let capture_sample_rate = config.capture_sample_rate.unwrap_or(48_000);
+    let playback_channels = config.playback_channels.unwrap_or(2);
+    let playback_sample_rate = config.playback_sample_rate.unwrap_or(48_000);
+    let bitrate = config.bitrate.unwrap_or(64_000);
+    let threshold = config.threshold.unwrap_or(0);
+    let server_options = ServerOptions {
+        listen,
+        public,
+        relay,
+        relay_bandwidth,
+        heartbeat_interval,
+        heartbeat_timeout,
+        max_peers,
+        rooms,
+        forwarding,
+        max_speakers,
+        require_invite,
+        invite_secret,
+    };
+    //--username --capture --playback --server --key: the arguments of host and join given as options
+    let username_option = take_option(&mut args, "--username");
+    let capture_option = take_option(&mut args, "--capture");
+    let playback_option = take_option(&mut args, "--playback");
+    let server_option = take_option(&mut args, "--server");
+    let key_option = take_option(&mut args, "--key");
+    //--invite <link>: join with an invite link instead of the server address and key, it can be given
+    //as an argument in their place too
+    let invite_option = take_option(&mut args, "--invite");
+    cli::check_unknown_options(&args);
+    //args: <command> <arguments>, see cli::Command
+    let command = match args.first() {
+        Some(arg) => Command::from_text(arg).unwrap_or_else(|| cli::fail(&format!("Unknown command {}", arg))),
+        None => cli::fail("Missing command"),
+    };
+    if args.len() - 1 > command.max_arguments() {
+        cli::fail(&format!("Too many arguments for {}", args[0]));
+    }
+    match command {
+        //serve: a headless server, it isn't in any room and only reads the invite commands (op_code 9 and 10) from stdin
+        Command::Serve => {
+            serve(&server_options, DeviceSelection::new("_", playback_channels, playback_sample_rate));
+            return;
+        }
+        Command::Keygen => {
+            let key = aes::AES::new(None).unwrap().get_key();
+            println!("{}", json!({ "event_code": 21, "key": key }));
+            return;
+        }
+        Command::Devices => {
+            Audio::print_devices();
+            return;
+        }
+        _ => {}
+    }
     //stdin handler
-    let (stdin_tx, stdin_rx) = flume::bounded::<(u8, u8, u8, u16, u16, Option<String>)>(1);
+    let (stdin_tx, stdin_rx) = flume::bounded::<StdinCommand>(1);
+    //The control commands of a server, passed on as they were read
+    let (control_tx, control_rx) = flume::bounded::<Value>(1);
     spawn_thread!("stdin thread" ,move || {
         loop {
             //format { "op_code": n, ... }
@@ -50,109 +568,209 @@ fn main() {
                     let _ = stdin_tx.send((op_code, channels, 0, sample_rate, 0, Some(device)));
                 }
                 2 => {
-                    let peer_id = parsed["peer_id"].as_u64().unwrap() as u8;
+                    let peer_id = parsed["peer_id"].as_u64().unwrap() as u16;
                     let volume = parsed["volume"].as_u64().unwrap() as u8;
-                    let _ = stdin_tx.send((op_code, peer_id, volume, 0, 0, None));
+                    let _ = stdin_tx.send((op_code, 0, volume, peer_id, 0, None));
                 }
                 3 => {
                     let bitrate = parsed["bitrate"].as_u64().unwrap() as u16;
                     let _ = stdin_tx.send((op_code, 0, 0, bitrate, 0, None));
                 }
+                4 => {
+                    let backends = parsed["backends"]
+                        .as_array()
+                        .and_then(|backends| backends.iter().map(|backend| backend.as_str()).collect::<Option<Vec<&str>>>());
+                    match backends {
+                        Some(backends) => {
+                            let _ = stdin_tx.send((op_code, 0, 0, 0, 0, Some(backends.join(","))));
+                        }
+                        None => println!(
+                            "{}",
+                            json!({ "event_code": -1, "error": "op_code 4 needs an array of backend names \"backends\"" })
+                        ),
+                    }
+                }
+                5 => {
+                    Audio::print_devices();
+                }
+                6 | 7 => {
+                    let field = if op_code == 6 { "muted" } else { "deafened" };
+                    match parsed[field].as_bool() {
+                        Some(state) => {
+                            let _ = stdin_tx.send((op_code, state as u8, 0, 0, 0, None));
+                        }
+                        None => println!(
+                            "{}",
+                            json!({ "event_code": -1, "error": format!("op_code {} needs a boolean \"{}\"", op_code, field) })
+                        ),
+                    }
+                }
+                8 => {
+                    roster::print();
+                }
+                9 | 10 => {
+                    let _ = control_tx.send(parsed);
+                }
 
                 _ => {}
             }
         }
     });
 
-    //args: <0 or 1 for server or client>
-    //server: <username> <input device name> <output device name>
-    //client: <username> <server address> <server key> <input device name> <output device name>
-    let op = args[0].parse::<u8>().unwrap();
-    match op {
-        0 => {
+    //host: <username> <input device name> <output device name>
+    //join: <username> <server address> <server key> <input device name> <output device name>
+    //Each argument can be given as an option instead, or omitted when the config file has it
+    match command {
+        Command::Host => {
             //Server
-            let username = args[1].clone();
-            let input_device_name = args[2].clone();
-            let output_device_name = args[3].clone();
-            let capture_device_config = AudioCapture::create_config(input_device_name, 1, 48_000);
-            let mut capture = AudioCapture::new(capture_device_config, 64_000, 0);
+            let [username, input_device_name, output_device_name]: [String; 3] = cli::resolve_arguments(
+                vec![
+                    ("username", username_option, config.username.clone()),
+                    ("capture device", capture_option, config.capture.clone()),
+                    ("playback device", playback_option, config.playback.clone()),
+                ],
+                &args[1..],
+            )
+            .try_into()
+            .unwrap();
+            if let Err(e) = Audio::get_device_id(&output_device_name, DeviceKind::Playback) {
+                Audio::print_device_error(&e);
+                return;
+            }
+            let capture_selection = DeviceSelection::new(&input_device_name, capture_channels, capture_sample_rate);
+            let capture = match AudioCapture::new(capture_selection, bitrate, threshold) {
+                Ok(capture) => capture,
+                Err(e) => {
+                    Audio::print_device_error(&e);
+                    return;
+                }
+            };
             let capture_rx = capture.get_capture_rx();
-            capture.start();
+            if let Err(e) = capture.start() {
+                Audio::print_device_error(&e);
+                return;
+            }
 
-            let server = SignalingServer::new(username);
-            println!(
-                "{{ \"event_code\": 0, \"server_address\": \"{}\", \"server_key\": \"{}\" }}",
-                server.get_listen_address(),
-                server.get_cipher_key()
-            );
-            server.run(output_device_name);
-            loop {
-                if let Ok(data) = stdin_rx.try_recv() {
-                    match data.0 {
-                        0 => {
-                            capture.change_device(data.5.unwrap(), data.1 as u32, data.3 as u32);
-                        }
-                        1 => {
-                            server.change_playback(&data.5.unwrap(), data.1 as u32, data.2 as u32);
-                        }
-                        2 => {
-                            server.change_peer_volume(data.1, data.2);
-                        }
-                        3 => {
-                            capture.set_encoder_bitrate(data.3 as i32);
-                        }
-                        _ => {}
+            let server = match start_server(Some(username.clone()), &server_options) {
+                Some(server) => Arc::new(server),
+                None => return,
+            };
+            roster::set_self(0, &username);
+            server.run(DeviceSelection::new(&output_device_name, playback_channels, playback_sample_rate));
+            //The invite commands get their own thread, so they're answered whatever the audio loop waits on
+            let control_server = server.clone();
+            spawn_thread!("server control", move || {
+                while let Ok(parsed) = control_rx.recv() {
+                    if parsed["op_code"].as_u64() == Some(9) {
+                        create_invite(&control_server, &parsed);
+                    } else {
+                        revoke_invites(&control_server, &parsed);
                     }
                 }
-                if let Ok(data) = capture_rx.recv() {
-                    server.send_opus(data);
+            });
+            spawn_thread!("nat type detection", signaling::print_nat_types);
+            let watcher = DeviceWatcher::new();
+            let device_rx = watcher.get_event_rx();
+            watcher.start(DEVICE_POLL_INTERVAL);
+            let mut session = AudioSession::new(capture, server.get_audio_peers(), server.get_playback(), failover);
+            loop {
+                session.poll(&stdin_rx, &device_rx, || server.send_presence());
+                //Silence sends nothing, so don't wait for it to keep handling stdin and the devices
+                if let Ok(data) = capture_rx.recv_timeout(std::time::Duration::from_millis(9)) {
+                    if !roster::is_muted() {
+                        roster::voice(0);
+                        server.send_opus(data);
+                    }
                 }
                 thread::sleep(std::time::Duration::from_millis(1));
             }
         }
-        1 => {
+        Command::Join => {
             //Client
-            let username = args[1].clone();
-            let server_address = args[2].clone();
-            let server_key = args[3].clone();
-            let input_device_name = args[4].clone();
-            let output_device_name = args[5].clone();
-            let capture_device_config = AudioCapture::create_config(input_device_name, 1, 48_000);
-            let mut capture = AudioCapture::new(capture_device_config, 64_000, 0);
+            //An invite link stands in for the server address and the key, and names the room
+            let link = invite_option.or_else(|| {
+                let pos = args.iter().position(|arg| arg.starts_with(INVITE_SCHEME))?;
+                Some(args.remove(pos))
+            });
+            let link = match link.map(|link| InviteLink::parse(&link)).transpose() {
+                Ok(link) => link,
+                Err(e) => cli::fail(&format!("Invalid invite link: {}", e)),
+            };
+            let (server_option, key_option, client_room, invite_token) = match link {
+                Some(link) => (
+                    server_option.or(Some(link.addresses)),
+                    key_option.or(Some(link.key)),
+                    link.room,
+                    link.token,
+                ),
+                None => (server_option, key_option, client_room, None),
+            };
+            let [username, server_address, server_key, input_device_name, output_device_name]: [String; 5] =
+                cli::resolve_arguments(
+                    vec![
+                        ("username", username_option, config.username.clone()),
+                        ("server address", server_option, config.server_address.clone()),
+                        ("server key", key_option, config.server_key.clone()),
+                        ("capture device", capture_option, config.capture.clone()),
+                        ("playback device", playback_option, config.playback.clone()),
+                    ],
+                    &args[1..],
+                )
+                .try_into()
+                .unwrap();
+            if let Err(e) = Audio::get_device_id(&output_device_name, DeviceKind::Playback) {
+                Audio::print_device_error(&e);
+                return;
+            }
+            let capture_selection = DeviceSelection::new(&input_device_name, capture_channels, capture_sample_rate);
+            let capture = match AudioCapture::new(capture_selection, bitrate, threshold) {
+                Ok(capture) => capture,
+                Err(e) => {
+                    Audio::print_device_error(&e);
+                    return;
+                }
+            };
             let capture_rx = capture.get_capture_rx();
-            capture.start();
+            if let Err(e) = capture.start() {
+                Audio::print_device_error(&e);
+                return;
+            }
 
-            let client = SignalingClient::new(username, &server_address, &server_key);
-            client.run(output_device_name);
+            //The server key is the key of the room when a room is named
+            let client = match SignalingClient::new(
+                username.clone(),
+                &server_address,
+                &server_key,
+                &client_room,
+                invite_token.as_deref(),
+            ) {
+                Ok(client) => client,
+                Err(e) => {
+                    println!("{}", json!({ "event_code": -1, "error": format!("Failed to connect to server: {}", e) }));
+                    return;
+                }
+            };
+            roster::set_self(client.get_id(), &username);
+            client.run(DeviceSelection::new(&output_device_name, playback_channels, playback_sample_rate));
+            spawn_thread!("nat type detection", signaling::print_nat_types);
+            let watcher = DeviceWatcher::new();
+            let device_rx = watcher.get_event_rx();
+            watcher.start(DEVICE_POLL_INTERVAL);
+            let mut session = AudioSession::new(capture, client.get_audio_peers(), client.get_playback(), failover);
             loop {
-                if let Ok(data) = stdin_rx.try_recv() {
-                    match data.0 {
-                        0 => {
-                            capture.change_device(data.5.unwrap(), data.1 as u32, data.3 as u32);
-                        }
-                        1 => {
-                            client.change_playback(&data.5.unwrap(), data.1 as u32, data.2 as u32);
-                        }
-                        2 => {
-                            client.change_peer_volume(data.1, data.2);
-                        }
-                        3 => {
-                            capture.set_encoder_bitrate(data.3 as i32);
-                        }
-                        _ => {}
-                    }
+                session.poll(&stdin_rx, &device_rx, || client.send_presence());
+                if control_rx.try_recv().is_ok() {
+                    println!("{{ \"event_code\": -1, \"error\": \"Only a server handles invites\" }}");
                 }
                 if let Ok(data) = capture_rx.recv_timeout(std::time::Duration::from_millis(9)) {
-                    client.send_opus(data);
+                    if !roster::is_muted() {
+                        roster::voice(client.get_id());
+                        client.send_opus(data);
+                    }
                 }
                 thread::sleep(std::time::Duration::from_millis(1));
             }
         }
-        3 => {
-            Audio::print_devices();
-        }
-        _ => {
-            panic!("Invalid operation");
-        }
+        _ => {}
     }
 }
@@ -0,0 +1,201 @@
+// SPDX-FileCopyrightText: Copyright 2023 tSVoI
+// SPDX-License-Identifier: GPL-3.0-only
+
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::{Mutex, Once};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::signaling::PeerId;
+use crate::spawn_thread;
+
+/// A peer stops speaking when no voice arrives for this long
+const SPEAKING_HOLD: Duration = Duration::from_millis(300);
+/// Time between two checks for peers that stopped speaking
+const SPEAKING_POLL: Duration = Duration::from_millis(100);
+
+/// Presence flags sent in announce, acknowledge and presence messages
+pub const MUTED: u8 = 1;
+pub const DEAFENED: u8 = 2;
+
+/// What is known about a peer in the room
+struct Member {
+    username: String,
+    /// The candidate type of the path, None until a path is chosen
+    path: Option<&'static str>,
+    state: &'static str,
+    rtt_ms: u64,
+    muted: bool,
+    deafened: bool,
+    speaking: bool,
+    last_voice: Option<Instant>,
+}
+impl Member {
+    fn new(username: &str, flags: u8) -> Self {
+        Member {
+            username: username.to_string(),
+            path: None,
+            state: "connecting",
+            rtt_ms: 0,
+            muted: flags & MUTED != 0,
+            deafened: flags & DEAFENED != 0,
+            speaking: false,
+            last_voice: None,
+        }
+    }
+
+    fn to_json(&self, id: PeerId) -> Value {
+        json!({
+            "id": id,
+            "username": self.username,
+            "path": self.path,
+            "state": self.state,
+            "rtt_ms": self.rtt_ms,
+            "muted": self.muted,
+            "deafened": self.deafened,
+            "speaking": self.speaking,
+        })
+    }
+}
+
+/// Every peer in the room, ourselves included
+static MEMBERS: Mutex<BTreeMap<PeerId, Member>> = Mutex::new(BTreeMap::new());
+static SELF_ID: AtomicU16 = AtomicU16::new(0);
+static SPEAKING_WATCH: Once = Once::new();
+
+/// Adds ourselves to the roster, or moves our entry when the server gave us another id.
+/// The first call starts watching for peers that stop speaking.
+/// # Arguments
+/// * `id` - Our peer id
+/// * `username` - Our username
+pub fn set_self(id: PeerId, username: &str) {
+    let mut members = MEMBERS.lock().unwrap();
+    let previous = SELF_ID.swap(id, Ordering::Relaxed);
+    let mut member = members.remove(&previous).unwrap_or_else(|| Member::new(username, 0));
+    member.state = "connected";
+    members.insert(id, member);
+    drop(members);
+    SPEAKING_WATCH.call_once(|| {
+        spawn_thread!("roster speaking watch", || loop {
+            thread::sleep(SPEAKING_POLL);
+            let mut members = MEMBERS.lock().unwrap();
+            for (id, member) in members.iter_mut() {
+                let recent = member.last_voice.is_some_and(|last| last.elapsed() < SPEAKING_HOLD);
+                if member.speaking && !recent {
+                    member.speaking = false;
+                    print_presence(*id, "speaking", false);
+                }
+            }
+        });
+    });
+}
+
+/// Returns our peer id
+pub fn get_self_id() -> PeerId {
+    SELF_ID.load(Ordering::Relaxed)
+}
+
+/// Adds a peer that connected and prints it
+/// # Arguments
+/// * `id` - The peer id
+/// * `username` - The username it announced
+/// * `flags` - Its presence flags
+pub fn join(id: PeerId, username: &str, flags: u8) {
+    MEMBERS.lock().unwrap().insert(id, Member::new(username, flags));
+    println!("{}", json!({ "event_code": 2, "id": id, "username": username }));
+}
+
+/// Removes a peer that left and prints it
+pub fn leave(id: PeerId) {
+    MEMBERS.lock().unwrap().remove(&id);
+    println!("{}", json!({ "event_code": 3, "id": id }));
+}
+
+/// Records the candidate type of the path to a peer, already reported by events 10 and 13
+pub fn set_path(id: PeerId, kind: &'static str) {
+    if let Some(member) = MEMBERS.lock().unwrap().get_mut(&id) {
+        member.path = Some(kind);
+    }
+}
+
+/// Records the state of the path to a peer, already reported by event 14
+pub fn set_state(id: PeerId, state: &'static str, rtt_ms: u64) {
+    if let Some(member) = MEMBERS.lock().unwrap().get_mut(&id) {
+        member.state = state;
+        member.rtt_ms = rtt_ms;
+    }
+}
+
+/// Updates the mute and deafen state of a peer and prints what changed
+/// # Arguments
+/// * `id` - The peer id
+/// * `flags` - Its presence flags
+pub fn set_presence(id: PeerId, flags: u8) {
+    let mut members = MEMBERS.lock().unwrap();
+    let member = match members.get_mut(&id) {
+        Some(member) => member,
+        None => return,
+    };
+    let (muted, deafened) = (flags & MUTED != 0, flags & DEAFENED != 0);
+    if member.muted != muted {
+        member.muted = muted;
+        print_presence(id, "muted", muted);
+    }
+    if member.deafened != deafened {
+        member.deafened = deafened;
+        print_presence(id, "deafened", deafened);
+    }
+}
+
+/// Returns our presence flags
+pub fn get_self_flags() -> u8 {
+    let members = MEMBERS.lock().unwrap();
+    match members.get(&get_self_id()) {
+        Some(member) => (member.muted as u8 * MUTED) | (member.deafened as u8 * DEAFENED),
+        None => 0,
+    }
+}
+
+/// Returns true if we shouldn't send our voice, deafened implies muted
+pub fn is_muted() -> bool {
+    get_self_flags() != 0
+}
+
+/// Returns true if we shouldn't play the voice of the peers
+pub fn is_deafened() -> bool {
+    get_self_flags() & DEAFENED != 0
+}
+
+/// Marks a peer as speaking, called for each voice packet it sends or we send
+pub fn voice(id: PeerId) {
+    let mut members = MEMBERS.lock().unwrap();
+    if let Some(member) = members.get_mut(&id) {
+        member.last_voice = Some(Instant::now());
+        if !member.speaking {
+            member.speaking = true;
+            print_presence(id, "speaking", true);
+        }
+    }
+}
+
+fn print_presence(id: PeerId, field: &str, value: bool) {
+    let mut event = json!({ "event_code": 19, "id": id });
+    event[field] = json!(value);
+    println!("{}", event);
+}
+
+/// Prints every peer in the room
+pub fn print() {
+    let members = MEMBERS.lock().unwrap();
+    let peers: Vec<Value> = members.iter().map(|(id, member)| member.to_json(*id)).collect();
+    println!(
+        "{}",
+        json!({
+            "event_code": 20,
+            "self": get_self_id(),
+            "peers": peers,
+        })
+    );
+}
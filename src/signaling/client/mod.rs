@@ -1,108 +1,430 @@
 // SPDX-FileCopyrightText: Copyright 2023 tSVoI
 // SPDX-License-Identifier: GPL-3.0-only
 use bytes::{BufMut, Bytes, BytesMut};
+use serde_json::json;
+use aead::rand_core::RngCore;
+use aead::OsRng;
 use std::collections::HashMap;
-use std::io::{Read, Write};
-use std::net::TcpStream;
+use std::io::Write;
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicU16, Ordering};
 use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::Duration;
 
 use crate::aes::AES;
 use crate::audio::playback;
-use crate::audio_peer::AudioPeer;
-use crate::signaling;
+use crate::audio::device::DeviceSelection;
+use crate::audio_peer::{AudioPeer, ForwardedVoice};
+use crate::ice;
+use crate::roster;
+use crate::signaling::invite::{PresentedInvite, Refusal, INVITE_FLAG};
+use crate::signaling::room::MAX_ROOM_NAME;
+use crate::signaling::{self, PeerId, Roster, HEADER_LEN, MAX_USERNAME_LEN};
 use crate::spawn_thread;
 
+/// Time to wait for each server address
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+/// Wait before the first reconnection attempt, doubled after each failed attempt
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+/// Longest wait between two reconnection attempts
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(30);
+
+/// Where the client connects to
+struct Endpoint {
+    /// The server addresses, tried in order
+    addresses: Vec<String>,
+    /// The room to join, empty for the room of the server
+    room: String,
+    /// The invite token of the link we joined with and our session, sent again on each
+    /// reconnection so a single-use invite still lets us in
+    invite: Option<PresentedInvite>,
+}
+
+/// What the server tells a client that joins
+struct Welcome {
+    id: PeerId,
+    /// The peers in the room, with their usernames
+    roster: Roster,
+    /// Sent back with the id after a reconnection, so no other client can take it
+    resume_token: u64,
+    /// Where our voice goes in a forwarding room, None if the peers mesh
+    forwarded: Option<ForwardedVoice>,
+}
+
 pub struct SignalingClient {
-    id: u8,
+    /// Our peer id, it changes if the server can't give it back after a reconnection
+    id: Arc<AtomicU16>,
     username: String,
-    stream: TcpStream,
+    /// The peers in the room when we joined, with their usernames
+    roster: Roster,
+    /// The token the server gave with our id when we joined
+    resume_token: u64,
+    /// The server addresses and the room, used again when connecting again
+    endpoint: Arc<Endpoint>,
+    /// The stream messages are written to, replaced after a reconnection
+    stream: Arc<Mutex<TcpStream>>,
     cipher: Arc<AES>,
-    audio_peers: Arc<Mutex<HashMap<u8, AudioPeer>>>,
+    audio_peers: Arc<Mutex<HashMap<PeerId, AudioPeer>>>,
+    /// Our voice goes there instead of to each peer, replaced after a reconnection
+    forwarded: Arc<Mutex<Option<ForwardedVoice>>>,
+    playback: Arc<Mutex<DeviceSelection>>,
 }
 impl SignalingClient {
-    pub fn new(username: String, address: &str, key: &str) -> Self {
-        let cipher = Arc::new(AES::new(Some(key)).unwrap());
+    /// Connects to a server and receives the peer id
+    /// # Arguments
+    /// * `username` - The name shown to the other peers
+    /// * `address` - The server address, or a comma separated list of addresses tried in order
+    /// * `key` - The key of the room
+    /// * `room` - The room to join, empty for the room of the server
+    /// * `invite` - The invite token of the link we join with, None to join with the key alone
+    pub fn new(
+        username: String,
+        address: &str,
+        key: &str,
+        room: &str,
+        invite: Option<&str>,
+    ) -> Result<Self, std::io::Error> {
+        let cipher = Arc::new(AES::new(Some(key)).map_err(|e| {
+            std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("Invalid server key: {}", e))
+        })?);
+        if username.len() > MAX_USERNAME_LEN {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("The username is longer than {} bytes", MAX_USERNAME_LEN),
+            ));
+        }
+        if room.len() > MAX_ROOM_NAME {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("The room name is longer than {} bytes", MAX_ROOM_NAME),
+            ));
+        }
+        if invite.is_some_and(|token| token.len() > u8::MAX as usize) {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "The invite token is too long"));
+        }
         let audio_peers = Arc::new(Mutex::new(HashMap::new()));
-        let try_stream = TcpStream::connect(address);
-        if try_stream.is_err() {
-            error!("Err: {:?}", try_stream.err());
-            panic!("Failed to connect to server");
-        } else {
-            debug!("Connected to server");
-        }
-        let mut stream = try_stream.unwrap();
-        //stream.write(&[1]);
-        thread::sleep(std::time::Duration::from_millis(10));
-
-        let recv_buffer = &mut [0u8; 1024];
-        let try_recv = stream.read(recv_buffer);
-        if try_recv.is_err() {
-            panic!("Failed to read to buffer");
-        }
-        let size = try_recv.unwrap();
-        let encrypted = recv_buffer[..size].to_vec();
-        let try_decrypt = cipher.decrypt(Bytes::from(encrypted));
-        if try_decrypt.is_err() {
-            panic!("Failed to decrypt id");
-        }
-        let decrypted = try_decrypt.unwrap();
-        let opcode = decrypted[0];
-        if opcode != 0 {
-            panic!("Invalid opcode");
-        }
-
-        let id = decrypted[1];
+        let endpoint = Endpoint {
+            addresses: signaling::split_candidates(address),
+            room: room.to_string(),
+            invite: invite.map(|token| (token.to_string(), OsRng.next_u64())),
+        };
+        let (stream, welcome) = Self::handshake(&endpoint, &cipher, None)?;
+        let (id, roster, resume_token) = (welcome.id, welcome.roster, welcome.resume_token);
+        debug!("Connected to server");
         debug!("Peer id is {}", id);
-        SignalingClient {
-            id,
+        Ok(SignalingClient {
+            id: Arc::new(AtomicU16::new(id)),
             username,
-            stream,
+            roster,
+            resume_token,
+            endpoint: Arc::new(endpoint),
+            stream: Arc::new(Mutex::new(stream)),
             cipher,
             audio_peers,
+            forwarded: Arc::new(Mutex::new(welcome.forwarded)),
+            playback: Arc::new(Mutex::new(DeviceSelection::new("_", 2, 48_000))),
+        })
+    }
+
+    /// Connects to the server, names the room and reads the peer id and the roster. The welcome
+    /// message is encrypted with the room key, on the first connection a welcome that doesn't
+    /// decrypt means the key is wrong.
+    /// # Arguments
+    /// * `endpoint` - The server addresses and the room
+    /// * `cipher` - The room cipher
+    /// * `previous` - The id to ask back after a reconnection and its resume token, the server
+    ///   gives it back if it's free and the token matches
+    fn handshake(
+        endpoint: &Endpoint,
+        cipher: &AES,
+        previous: Option<(PeerId, u64)>,
+    ) -> Result<(TcpStream, Welcome), std::io::Error> {
+        let mut stream = Self::connect(&endpoint.addresses)?;
+        stream.set_read_timeout(Some(CONNECT_TIMEOUT))?;
+        //The hello is in clear, the server needs the room to know the key
+        let mut hello_msg = BytesMut::with_capacity(1 + endpoint.room.len() + 265);
+        match &endpoint.invite {
+            Some((token, session)) => {
+                hello_msg.put_u8(endpoint.room.len() as u8 | INVITE_FLAG);
+                hello_msg.put(endpoint.room.as_bytes());
+                hello_msg.put_u8(token.len() as u8);
+                hello_msg.put(token.as_bytes());
+                hello_msg.put_u64(*session);
+            }
+            None => {
+                hello_msg.put_u8(endpoint.room.len() as u8);
+                hello_msg.put(endpoint.room.as_bytes());
+            }
+        }
+        stream.write_all(&hello_msg)?;
+        let mut welcome = Self::read_welcome(&mut stream, cipher, previous.is_none()).map_err(|e| {
+            if e.kind() == std::io::ErrorKind::UnexpectedEof && !endpoint.room.is_empty() {
+                std::io::Error::new(e.kind(), format!("{}, there may be no room \"{}\"", e, endpoint.room))
+            } else {
+                e
+            }
+        })?;
+        if let Some((previous_id, resume_token)) = previous {
+            if previous_id != welcome.id {
+                let mut resume_msg = BytesMut::with_capacity(HEADER_LEN + 10);
+                signaling::put_header(&mut resume_msg, 8, welcome.id, 0);
+                resume_msg.put_u16(previous_id);
+                resume_msg.put_u64(resume_token);
+                signaling::write_message(&mut stream, cipher, resume_msg)?;
+                welcome = Self::read_welcome(&mut stream, cipher, false)?;
+            }
         }
+        stream.set_read_timeout(None)?;
+        debug!("Peers in the room: {:?}", welcome.roster);
+        Ok((stream, welcome))
     }
-    pub fn run(&self, playback_name: String) {
-        let mut stream = self.stream.try_clone().unwrap();
-        let audio_peers = self.audio_peers.clone();
-        //Announce
+
+    /// Reads messages until the server sends an id and the roster, or turns us away when the room
+    /// is full or our invite isn't valid
+    /// # Arguments
+    /// * `check_key` - True if a first message that doesn't decrypt means the key is wrong, later
+    ///   failures are retryable
+    fn read_welcome(stream: &mut TcpStream, cipher: &AES, check_key: bool) -> Result<Welcome, std::io::Error> {
+        let mut first = check_key;
+        loop {
+            let frame = signaling::read_frame(stream).map_err(|e| {
+                if e.kind() == std::io::ErrorKind::UnexpectedEof {
+                    std::io::Error::new(e.kind(), "The server closed the connection")
+                } else {
+                    e
+                }
+            })?;
+            let decrypted = cipher.decrypt_vec(frame).map_err(|_| {
+                if first {
+                    std::io::Error::new(std::io::ErrorKind::PermissionDenied, "Wrong server key")
+                } else {
+                    std::io::Error::new(std::io::ErrorKind::InvalidData, "A message of the server didn't decrypt")
+                }
+            })?;
+            first = false;
+            if decrypted.len() < 2 {
+                continue;
+            }
+            match decrypted[0] {
+                0 | 10 if decrypted.len() < 3 => continue,
+                0 => {
+                    let (roster, roster_len) = signaling::parse_roster(&decrypted[3..]).ok_or_else(|| {
+                        std::io::Error::new(std::io::ErrorKind::InvalidData, "Truncated roster")
+                    })?;
+                    let rest = &decrypted[3 + roster_len..];
+                    let resume_token = rest.get(..8).ok_or_else(|| {
+                        std::io::Error::new(std::io::ErrorKind::InvalidData, "Truncated welcome")
+                    })?;
+                    return Ok(Welcome {
+                        id: signaling::read_id(&decrypted, 1),
+                        roster,
+                        resume_token: u64::from_be_bytes(resume_token.try_into().unwrap()),
+                        forwarded: Self::read_forwarding(&rest[8..], cipher),
+                    });
+                }
+                10 => {
+                    let max_peers = u16::from_be_bytes([decrypted[1], decrypted[2]]);
+                    println!("{{ \"event_code\": 18, \"max_peers\": {} }}", max_peers);
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::ConnectionRefused,
+                        format!("The room is full ({} peers)", max_peers),
+                    ));
+                }
+                11 => {
+                    let reason = Refusal::from_u8(decrypted[1]).map(Refusal::to_text).unwrap_or("unknown");
+                    println!("{{ \"event_code\": 23, \"reason\": \"{}\" }}", reason);
+                    //Trying again won't make the invite valid
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::PermissionDenied,
+                        format!("The server refused the invite ({})", reason),
+                    ));
+                }
+                //Other peers may leave before the id is read
+                _ => continue,
+            }
+        }
+    }
+
+    /// Reads the end of the welcome of a forwarding room, `<u64 token><str server addresses>`
+    /// # Returns
+    /// * Where our voice goes, None if the room isn't a forwarding room
+    fn read_forwarding(msg: &[u8], cipher: &AES) -> Option<ForwardedVoice> {
+        let token = u64::from_be_bytes(msg.get(..8)?.try_into().ok()?);
+        let relays: Vec<SocketAddr> = signaling::split_candidates(std::str::from_utf8(&msg[8..]).ok()?)
+            .iter()
+            .filter_map(|relay| relay.parse().ok())
+            .collect();
+        let forwarded = ForwardedVoice::new(&relays, token, cipher.get_key());
+        if forwarded.is_none() {
+            error!("Can't reach any address of the forwarding server {:?}", relays);
+        }
+        forwarded
+    }
+
+    /// Connects again with an exponential backoff until the server answers
+    /// # Arguments
+    /// * `endpoint` - The server addresses and the room
+    /// * `cipher` - The room cipher
+    /// * `previous` - The id to ask back and its resume token
+    /// # Returns
+    /// * The new stream and welcome, None if the server refused the invite
+    fn reconnect(
+        endpoint: &Endpoint,
+        cipher: &AES,
+        previous: (PeerId, u64),
+    ) -> Option<(TcpStream, Welcome)> {
+        let mut delay = RECONNECT_DELAY;
+        let mut attempt: u32 = 1;
+        loop {
+            println!(
+                "{{ \"event_code\": 15, \"attempt\": {}, \"delay_ms\": {} }}",
+                attempt,
+                delay.as_millis()
+            );
+            thread::sleep(delay);
+            match Self::handshake(endpoint, cipher, Some(previous)) {
+                Ok(connected) => return Some(connected),
+                //Trying again won't make the invite valid, every other failure may be temporary
+                Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied => {
+                    println!("{}", json!({ "event_code": 17, "error": e.to_string() }));
+                    return None;
+                }
+                Err(e) => debug!("Reconnection attempt {} failed: {}", attempt, e),
+            }
+            delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+            attempt += 1;
+        }
+    }
+
+    /// Drops the peers of a lost connection, reconnects and announces to the peers again
+    /// # Arguments
+    /// * `endpoint` - The server addresses and the room
+    /// * `cipher` - The room cipher
+    /// * `previous` - The id before the connection was lost and its resume token
+    /// * `username` - The name shown to the other peers
+    /// * `audio_peers` - The audio peers, replaced by the announced ones
+    /// * `writer` - The shared stream, replaced by the new stream
+    /// * `shared_id` - The shared id, replaced by the new id
+    /// # Returns
+    /// * The new stream and welcome, None if the client gave up
+    fn resume(
+        endpoint: &Endpoint,
+        cipher: &AES,
+        previous: (PeerId, u64),
+        username: &str,
+        audio_peers: &Mutex<HashMap<PeerId, AudioPeer>>,
+        writer: &Mutex<TcpStream>,
+        shared_id: &AtomicU16,
+    ) -> Option<(TcpStream, Welcome)> {
+        let previous_peers: Vec<PeerId> = audio_peers.lock().unwrap().drain().map(|(id, _)| id).collect();
+        for lost_id in previous_peers {
+            roster::leave(lost_id);
+        }
+        let (stream, welcome) = Self::reconnect(endpoint, cipher, previous)?;
+        let id = welcome.id;
+        println!(
+            "{{ \"event_code\": 16, \"id\": {}, \"previous_id\": {} }}",
+            id, previous.0
+        );
+        roster::set_self(id, username);
+        *writer.lock().unwrap() = stream.try_clone().ok()?;
+        shared_id.store(id, Ordering::Relaxed);
+        let ids: Vec<PeerId> = welcome.roster.iter().map(|(peer_id, _)| *peer_id).collect();
+        Self::announce(writer, cipher, id, username, audio_peers, &ids);
+        Some((stream, welcome))
+    }
+
+    /// Creates an audio peer for each id and sends it an announce. The peers that were in the
+    /// room first are announced to, the peers that join later announce to us.
+    /// # Arguments
+    /// * `writer` - The shared signaling stream
+    /// * `cipher` - The server cipher
+    /// * `my_id` - Our peer id
+    /// * `username` - The name shown to the other peers
+    /// * `audio_peers` - The audio peers the new peers are added to
+    /// * `ids` - The ids to announce to
+    fn announce(
+        writer: &Mutex<TcpStream>,
+        cipher: &AES,
+        my_id: PeerId,
+        username: &str,
+        audio_peers: &Mutex<HashMap<PeerId, AudioPeer>>,
+        ids: &[PeerId],
+    ) {
         let mut unlocked_peers = audio_peers.lock().unwrap();
-        for i in 0..self.id {
-            let address_candidate = signaling::get_address_ipv6();
+        for &i in ids {
+            let audio_peer = AudioPeer::new(i, cipher.get_key());
+            let address_candidate = ice::candidates_to_text(audio_peer.get_candidates());
             let mut announce_msg = BytesMut::with_capacity(1024);
-            announce_msg.put_u8(1);
-            announce_msg.put_u8(self.id);
-            announce_msg.put_u8(i);
-            announce_msg.put_u8(address_candidate.len() as u8);
+            signaling::put_header(&mut announce_msg, 1, my_id, i);
+            announce_msg.put_u32(audio_peer.get_connection_id());
+            announce_msg.put_u16(address_candidate.len() as u16);
             announce_msg.put(address_candidate.as_bytes());
-            announce_msg.put(self.username.as_bytes());
-
-            let encrypted = self.cipher.encrypt(announce_msg).unwrap();
-            stream.write_all(&encrypted).unwrap();
+            announce_msg.put_u8(roster::get_self_flags());
+            announce_msg.put(username.as_bytes());
 
-            let audio_peer = AudioPeer::new(address_candidate, self.cipher.get_key());
+            if let Err(e) = signaling::write_message(&mut *writer.lock().unwrap(), cipher, announce_msg) {
+                error!("Failed to announce to {}: {}", i, e);
+                continue;
+            }
             unlocked_peers.insert(i, audio_peer);
         }
-        drop(unlocked_peers);
+    }
+
+    /// Connects to the first address that accepts the connection
+    fn connect(addresses: &[String]) -> Result<TcpStream, std::io::Error> {
+        let mut last_error = std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "No server address",
+        );
+        for address in addresses {
+            let addrs = match address.to_socket_addrs() {
+                Ok(addrs) => addrs,
+                Err(e) => {
+                    debug!("Couldn't resolve {}: {}", address, e);
+                    last_error = e;
+                    continue;
+                }
+            };
+            for addr in addrs {
+                match TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT) {
+                    Ok(stream) => return Ok(stream),
+                    Err(e) => {
+                        debug!("Couldn't connect to {}: {}", addr, e);
+                        last_error = e;
+                    }
+                }
+            }
+        }
+        Err(last_error)
+    }
+    pub fn run(&self, playback: DeviceSelection) {
+        *self.playback.lock().unwrap() = playback;
+        let playback = self.playback.clone();
+        let mut stream = self.stream.lock().unwrap().try_clone().unwrap();
+        let audio_peers = self.audio_peers.clone();
+        let ids: Vec<PeerId> = self.roster.iter().map(|(id, _)| *id).collect();
+        let mut my_id = self.get_id();
+        let mut resume_token = self.resume_token;
+        Self::announce(&self.stream, &self.cipher, my_id, &self.username, &audio_peers, &ids);
 
         let aes_clone = self.cipher.clone();
         let my_username = self.username.clone();
-        let my_id = self.id;
+        let endpoint = self.endpoint.clone();
+        let writer = self.stream.clone();
+        let shared_id = self.id.clone();
+        let forwarded = self.forwarded.clone();
+        //Only this thread reads the stream, every write goes through the writer lock
         spawn_thread!("client tpc signaling", move || {
-            let recv_buffer = &mut [0u8; 1024];
             let audio_peers = audio_peers.clone();
             println!("{{ \"event_code\": 1 }}");
-            let playback_name = playback_name.clone();
+            let playback = playback.clone();
             loop {
                 let audio_peers = audio_peers.clone();
-                match stream.read(recv_buffer) {
-                    Ok(recv_len) => {
-                        if recv_len == 0 {
-                            debug!("Connection closed");
-                            println!("{{ \"event_code\": 3  \"id\": 0 }}");
-                            return;
-                        }
-                        let try_decrypt = aes_clone.decrypt_vec(recv_buffer[..recv_len].to_vec());
+                match signaling::read_frame(&mut stream) {
+                    Ok(frame) => {
+                        let try_decrypt = aes_clone.decrypt_vec(frame);
                         if try_decrypt.is_err() {
                             error!("Failed to decrypt message: {}", try_decrypt.err().unwrap());
                             continue;
@@ -110,23 +432,30 @@ impl SignalingClient {
 
                         let decrypted = try_decrypt.unwrap();
                         debug!("Received message: {:?}", decrypted);
-                        let to_id = decrypted[2];
+                        let (opcode, from_id, to_id) = match signaling::parse_header(&decrypted) {
+                            Some(header) => header,
+                            None => continue,
+                        };
                         if to_id != my_id {
                             error!("Received message for peer {} {:?}", to_id, decrypted);
                             continue;
                         }
 
-                        let opcode = decrypted[0];
-                        let from_id = decrypted[1];
                         match opcode {
                             1 => {
-                                let payload = decrypted[3..].to_vec();
-                                let ip_len = 1 + payload[0] as usize;
-                                let ip_candidate = std::str::from_utf8(&payload[1..ip_len]).unwrap();
-                                let username = std::str::from_utf8(&payload[ip_len..]).unwrap();
-                                let my_addr_candidate = signaling::get_address_ipv6();
-                                let audio_peer =
-                                    AudioPeer::new(my_addr_candidate.clone(), aes_clone.get_key());
+                                let payload = &decrypted[HEADER_LEN..];
+                                let (remote_connection, ip_candidates, ip_len) = match signaling::read_announce(payload) {
+                                    Some(announce) => announce,
+                                    None => continue,
+                                };
+                                let flags = match payload.get(ip_len) {
+                                    Some(flags) => *flags,
+                                    None => continue,
+                                };
+                                let username = &String::from_utf8_lossy(&payload[ip_len + 1..]);
+                                let audio_peer = AudioPeer::new(from_id, aes_clone.get_key());
+                                let my_addr_candidate = ice::candidates_to_text(audio_peer.get_candidates());
+                                let my_connection = audio_peer.get_connection_id();
                                 audio_peers
                                     .lock()
                                     .unwrap()
@@ -134,38 +463,104 @@ impl SignalingClient {
 
                                 let unlocked_peers = audio_peers.lock().unwrap();
                                 let au = unlocked_peers.get(&from_id).unwrap();
-                                au.connect(ip_candidate, &playback_name);
-                                println!("{{ \"event_code\": 2, \"id\": {}, \"username\": \"{}\" }}", from_id, username);
+                                let playback = playback.lock().unwrap().clone();
+                                roster::join(from_id, username, flags);
+                                au.connect(&ip_candidates, remote_connection, &playback);
+                                Self::use_forwarding(au, &forwarded);
 
                                 let mut reply = BytesMut::with_capacity(1024);
-                                reply.put_u8(2);
-                                reply.put_u8(my_id);
-                                reply.put_u8(from_id);
-                                reply.put_u8(my_addr_candidate.len() as u8);
+                                signaling::put_header(&mut reply, 2, my_id, from_id);
+                                reply.put_u32(my_connection);
+                                reply.put_u16(my_addr_candidate.len() as u16);
                                 reply.put(my_addr_candidate.as_bytes());
+                                reply.put_u8(roster::get_self_flags());
                                 reply.put(my_username.as_bytes());
 
-                                let encrypted = aes_clone.encrypt(reply).unwrap();
-                                let _ = stream.write_all(&encrypted);
+                                let _ = signaling::write_message(&mut *writer.lock().unwrap(), &aes_clone, reply);
                             }
                             2 => {
-                                let payload = decrypted[3..].to_vec();
-                                let ip_len = 1 + payload[0] as usize;
-                                let ip_candidate = std::str::from_utf8(&payload[1..ip_len]).unwrap();
-                                let username = std::str::from_utf8(&payload[ip_len..]).unwrap();
+                                let payload = &decrypted[HEADER_LEN..];
+                                let (remote_connection, ip_candidates, ip_len) = match signaling::read_announce(payload) {
+                                    Some(announce) => announce,
+                                    None => continue,
+                                };
+                                let flags = match payload.get(ip_len) {
+                                    Some(flags) => *flags,
+                                    None => continue,
+                                };
+                                let username = &String::from_utf8_lossy(&payload[ip_len + 1..]);
                                 let unlocked_peers = audio_peers.lock().unwrap();
 
                                 let audio_peer = unlocked_peers.get(&from_id).unwrap();
-                                audio_peer.connect(ip_candidate, &playback_name);
-                                println!("{{ \"event_code\": 2, \"id\": {}, \"username\": \"{}\" }}", from_id, username);
+                                let playback = playback.lock().unwrap().clone();
+                                roster::join(from_id, username, flags);
+                                audio_peer.connect(&ip_candidates, remote_connection, &playback);
+                                Self::use_forwarding(audio_peer, &forwarded);
                             }
                             3 => {
                                 todo!("Change bitrate or let AudioPeer handle it");
                             }
                             4 => {
-                                let lost_id = decrypted[3];
-                                audio_peers.lock().unwrap().remove(&lost_id);
-                                println!("{{ \"event_code\": 3, \"id\": {} }}", lost_id);
+                                if decrypted.len() < HEADER_LEN + 2 {
+                                    continue;
+                                }
+                                let lost_id = signaling::read_id(&decrypted, HEADER_LEN);
+                                //Peers we never connected to aren't reported
+                                if audio_peers.lock().unwrap().remove(&lost_id).is_some() {
+                                    roster::leave(lost_id);
+                                }
+                            }
+                            5 => {
+                                if decrypted.len() < HEADER_LEN + 5 {
+                                    continue;
+                                }
+                                let peer_id = signaling::read_id(&decrypted, HEADER_LEN);
+                                let attempt = decrypted[HEADER_LEN + 2];
+                                let delay = Duration::from_millis(u16::from_be_bytes([decrypted[HEADER_LEN + 3], decrypted[HEADER_LEN + 4]]) as u64);
+                                let unlocked_peers = audio_peers.lock().unwrap();
+                                let audio_peer = match unlocked_peers.get(&peer_id) {
+                                    Some(audio_peer) => audio_peer,
+                                    None => continue,
+                                };
+                                //The server offers its relay when it has one
+                                if decrypted.len() >= HEADER_LEN + 13 {
+                                    let mut token = [0u8; 8];
+                                    token.copy_from_slice(&decrypted[HEADER_LEN + 5..HEADER_LEN + 13]);
+                                    let relays: Vec<SocketAddr> = signaling::split_candidates(std::str::from_utf8(&decrypted[HEADER_LEN + 13..]).unwrap_or(""))
+                                        .iter()
+                                        .filter_map(|relay| relay.parse().ok())
+                                        .collect();
+                                    audio_peer.add_relay(&relays, u64::from_be_bytes(token));
+                                }
+                                //Ask the server for another round if this one fails
+                                let writer = writer.clone();
+                                let aes = aes_clone.clone();
+                                audio_peer.punch(delay, attempt, move |attempt| {
+                                    let mut failed_msg = BytesMut::with_capacity(HEADER_LEN + 3);
+                                    signaling::put_header(&mut failed_msg, 6, my_id, 0);
+                                    failed_msg.put_u16(peer_id);
+                                    failed_msg.put_u8(attempt);
+                                    let _ = signaling::write_message(&mut *writer.lock().unwrap(), &aes, failed_msg);
+                                });
+                            }
+                            9 => {
+                                if decrypted.len() < HEADER_LEN + 4 {
+                                    continue;
+                                }
+                                //The server evicts us if we stay silent, we give up on it after the same time
+                                let mut timeout = [0u8; 4];
+                                timeout.copy_from_slice(&decrypted[HEADER_LEN..HEADER_LEN + 4]);
+                                let timeout = u32::from_be_bytes(timeout);
+                                let _ = stream.set_read_timeout(Some(Duration::from_millis(timeout as u64)));
+                                let mut heartbeat_msg = BytesMut::with_capacity(HEADER_LEN);
+                                signaling::put_header(&mut heartbeat_msg, 9, my_id, 0);
+                                let _ = signaling::write_message(&mut *writer.lock().unwrap(), &aes_clone, heartbeat_msg);
+                            }
+                            12 => {
+                                if decrypted.len() < HEADER_LEN + 1 {
+                                    continue;
+                                }
+                                roster::set_presence(from_id, decrypted[HEADER_LEN]);
                             }
                             _ => {
                                 error!("Unknown opcode {}", opcode);
@@ -174,15 +569,54 @@ impl SignalingClient {
                         }
                     }
                     Err(e) => {
-                        error!("Failed to read from stream: {}", e);
-                        return;
+                        if e.kind() == std::io::ErrorKind::UnexpectedEof {
+                            debug!("Connection closed");
+                        } else {
+                            error!("Failed to read from stream: {}", e);
+                        }
+                        match Self::resume(&endpoint, &aes_clone, (my_id, resume_token), &my_username, &audio_peers, &writer, &shared_id) {
+                            Some((new_stream, welcome)) => {
+                                (stream, my_id, resume_token) = (new_stream, welcome.id, welcome.resume_token);
+                                *forwarded.lock().unwrap() = welcome.forwarded;
+                            }
+                            None => return,
+                        }
                     }
                 }
             }
         });
     }
     
+    /// Returns our peer id
+    pub fn get_id(&self) -> PeerId {
+        self.id.load(Ordering::Relaxed)
+    }
+
+    /// Tells every peer our mute and deafen state, the server forwards it to the room
+    pub fn send_presence(&self) {
+        let mut presence_msg = BytesMut::with_capacity(HEADER_LEN + 1);
+        signaling::put_header(&mut presence_msg, 12, self.get_id(), 0);
+        presence_msg.put_u8(roster::get_self_flags());
+        if let Err(e) = signaling::write_message(&mut *self.stream.lock().unwrap(), &self.cipher, presence_msg) {
+            error!("Failed to send the presence: {}", e);
+        }
+    }
+
+    /// Sends the keepalives of a peer through the server in a forwarding room, there are no
+    /// punch rounds since the voice goes through the server anyway
+    fn use_forwarding(audio_peer: &AudioPeer, forwarded: &Mutex<Option<ForwardedVoice>>) {
+        if let Some(forwarded) = forwarded.lock().unwrap().as_ref() {
+            let (relay, token) = forwarded.get_relay();
+            audio_peer.use_relay(&[relay], token);
+        }
+    }
+
     pub fn send_opus(&self, opus_packet: Bytes) {
+        //One packet for the whole room in a forwarding room
+        if let Some(forwarded) = self.forwarded.lock().unwrap().as_ref() {
+            let _ = forwarded.send(opus_packet);
+            return;
+        }
         let trylock = self.audio_peers.try_lock();
         if trylock.is_err() {
             error!("Failed to lock audio peers, err:{}", trylock.err().unwrap());
@@ -194,21 +628,13 @@ impl SignalingClient {
         }
     }
 
-    pub fn change_playback(&self, device_name: &String, channels: u32, sample_rate: u32) {
-        let peers = self.audio_peers.lock().unwrap();
-        for peer in peers.values() {
-            peer.change_device(device_name, channels, sample_rate);
-        }
+    /// Returns the audio peers, the stdin commands change their volume and playback device
+    pub fn get_audio_peers(&self) -> Arc<Mutex<HashMap<PeerId, AudioPeer>>> {
+        self.audio_peers.clone()
     }
 
-    pub fn change_peer_volume(&self, peer_id: u8, volume: u8) {
-        let peers = self.audio_peers.lock().unwrap();
-        let peer = peers.get(&peer_id);
-        if peer.is_none() {
-            error!("Peer {} not found", peer_id);
-            return;
-        }
-        let peer = peer.unwrap();
-        peer.change_volume(volume);
+    /// Returns the playback device new peers are played on
+    pub fn get_playback(&self) -> Arc<Mutex<DeviceSelection>> {
+        self.playback.clone()
     }
 }
@@ -0,0 +1,298 @@
+// SPDX-FileCopyrightText: Copyright 2023 tSVoI
+// SPDX-License-Identifier: GPL-3.0-only
+
+use aead::rand_core::RngCore;
+use aead::OsRng;
+use bytes::{BufMut, Bytes, BytesMut};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::aes::{KeyError, AES};
+
+/// Every invite link starts with it
+pub const INVITE_SCHEME: &str = "tsvoi://";
+/// Set in the room length of the hello when an invite token follows the room name
+pub const INVITE_FLAG: u8 = 0x80;
+
+/// An invite token with the session of the client that brings it
+pub type PresentedInvite = (String, u64);
+
+/// Why the server turned away a client, sent as `<11><u8 reason>`. Unknown covers the tokens
+/// that weren't sealed by the server or were revoked with every other token.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Refusal {
+    /// The server only lets clients with an invite in
+    Missing = 1,
+    Unknown = 2,
+    Expired = 3,
+    /// A single-use invite another client already used
+    Used = 4,
+    /// The invite is for another room
+    WrongRoom = 5,
+    Revoked = 6,
+}
+impl Refusal {
+    pub fn from_u8(reason: u8) -> Option<Self> {
+        match reason {
+            1 => Some(Refusal::Missing),
+            2 => Some(Refusal::Unknown),
+            3 => Some(Refusal::Expired),
+            4 => Some(Refusal::Used),
+            5 => Some(Refusal::WrongRoom),
+            6 => Some(Refusal::Revoked),
+            _ => None,
+        }
+    }
+
+    pub fn to_text(self) -> &'static str {
+        match self {
+            Refusal::Missing => "missing",
+            Refusal::Unknown => "unknown",
+            Refusal::Expired => "expired",
+            Refusal::Used => "used",
+            Refusal::WrongRoom => "wrong room",
+            Refusal::Revoked => "revoked",
+        }
+    }
+}
+
+/// What a token says, sealed with the signing key of the server as
+/// `<u64 id><u64 expires, unix seconds, 0 for never><u8 single_use><str room>`
+struct Invite {
+    id: u64,
+    room: String,
+    /// None for an invite that never expires
+    expires: Option<SystemTime>,
+    single_use: bool,
+}
+impl Invite {
+    fn to_bytes(&self) -> Bytes {
+        let mut bytes = BytesMut::with_capacity(17 + self.room.len());
+        bytes.put_u64(self.id);
+        bytes.put_u64(self.expires.map(to_unix).unwrap_or(0));
+        bytes.put_u8(self.single_use as u8);
+        bytes.put(self.room.as_bytes());
+        bytes.freeze()
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let expires = u64::from_be_bytes(bytes.get(8..16)?.try_into().ok()?);
+        Some(Invite {
+            id: u64::from_be_bytes(bytes.get(..8)?.try_into().ok()?),
+            room: String::from_utf8(bytes.get(17..)?.to_vec()).ok()?,
+            expires: (expires != 0).then(|| UNIX_EPOCH + Duration::from_secs(expires)),
+            single_use: *bytes.get(16)? != 0,
+        })
+    }
+
+    fn is_expired(&self) -> bool {
+        is_past(self.expires)
+    }
+}
+
+/// The invite tokens of a server. A token is sealed with a signing key only the server knows, apart
+/// from the room keys, so the server checks it without storing it and nobody can forge or alter one.
+/// A token lets a client into one room until it expires, a single-use token only lets in the first
+/// client that uses it (and that client again when it reconnects). Tokens are revoked one by one,
+/// or all at once by changing the signing key.
+pub struct Invites {
+    signer: Mutex<AES>,
+    /// The ids of the revoked tokens, kept until the tokens expire
+    revoked: Mutex<HashMap<u64, Option<SystemTime>>>,
+    /// The session that used each single-use token, kept until the token expires
+    holders: Mutex<HashMap<u64, (u64, Option<SystemTime>)>>,
+    /// True if clients without an invite are turned away
+    required: AtomicBool,
+}
+impl Invites {
+    pub fn new() -> Self {
+        Invites {
+            signer: Mutex::new(AES::new(None).unwrap()),
+            revoked: Mutex::new(HashMap::new()),
+            holders: Mutex::new(HashMap::new()),
+            required: AtomicBool::new(false),
+        }
+    }
+
+    /// Replaces the random signing key, so the tokens stay valid after a restart
+    /// # Arguments
+    /// * `secret` - A key in the format of the room keys
+    /// # Errors
+    /// * The secret isn't a valid key, the random signing key is kept
+    pub fn set_secret(&self, secret: &str) -> Result<(), KeyError> {
+        *self.signer.lock().unwrap() = AES::new(Some(secret))?;
+        Ok(())
+    }
+
+    /// Turns away the clients that don't bring an invite, the room key alone isn't enough
+    pub fn set_required(&self, required: bool) {
+        self.required.store(required, Ordering::Relaxed);
+    }
+
+    /// Hands out a new invite token
+    /// # Arguments
+    /// * `room` - The room the token lets a client into
+    /// * `lifetime` - How long the token is valid, None for no limit
+    /// * `single_use` - True if only one client may use the token
+    /// # Returns
+    /// * The token and when it expires
+    pub fn issue(&self, room: &str, lifetime: Option<Duration>, single_use: bool) -> (String, Option<SystemTime>) {
+        let invite = Invite {
+            id: OsRng.next_u64(),
+            room: room.to_string(),
+            //Whole seconds, as the token carries them
+            expires: lifetime.map(|lifetime| UNIX_EPOCH + Duration::from_secs(to_unix(SystemTime::now() + lifetime))),
+            single_use,
+        };
+        let token = self.signer.lock().unwrap().encrypt_b64(invite.to_bytes()).unwrap();
+        (token, invite.expires)
+    }
+
+    /// Reads a token, None if it wasn't sealed with the current signing key
+    fn open(&self, token: &str) -> Option<Invite> {
+        let bytes = self.signer.lock().unwrap().decrypt_b64(token.to_string()).ok()?;
+        Invite::from_bytes(&bytes)
+    }
+
+    /// Checks the invite a client brings in its hello, a single-use invite is used up
+    /// # Arguments
+    /// * `room` - The room the client joins
+    /// * `presented` - The token and the session of the client, None if it brings no invite
+    /// # Returns
+    /// * Why the client is turned away
+    pub fn check(&self, room: &str, presented: Option<(&str, u64)>) -> Result<(), Refusal> {
+        let (token, session) = match presented {
+            Some(presented) => presented,
+            None if self.required.load(Ordering::Relaxed) => return Err(Refusal::Missing),
+            None => return Ok(()),
+        };
+        let invite = self.open(token).ok_or(Refusal::Unknown)?;
+        if invite.is_expired() {
+            return Err(Refusal::Expired);
+        }
+        if self.revoked.lock().unwrap().contains_key(&invite.id) {
+            return Err(Refusal::Revoked);
+        }
+        if invite.room != room {
+            return Err(Refusal::WrongRoom);
+        }
+        if invite.single_use {
+            let mut holders = self.holders.lock().unwrap();
+            holders.retain(|_, (_, expires)| !is_past(*expires));
+            match holders.get(&invite.id) {
+                Some((holder, _)) if *holder != session => return Err(Refusal::Used),
+                _ => holders.insert(invite.id, (session, invite.expires)),
+            };
+        }
+        Ok(())
+    }
+
+    /// Revokes a token, the clients that already joined with it stay but can't reconnect
+    /// # Returns
+    /// * Unknown if the token wasn't handed out with the current signing key
+    pub fn revoke(&self, token: &str) -> Result<(), Refusal> {
+        let invite = self.open(token).ok_or(Refusal::Unknown)?;
+        let mut revoked = self.revoked.lock().unwrap();
+        revoked.retain(|_, expires| !is_past(*expires));
+        revoked.insert(invite.id, invite.expires);
+        Ok(())
+    }
+
+    /// Revokes every token handed out so far by changing the signing key
+    pub fn revoke_all(&self) {
+        *self.signer.lock().unwrap() = AES::new(None).unwrap();
+        self.revoked.lock().unwrap().clear();
+        self.holders.lock().unwrap().clear();
+    }
+}
+
+/// Returns true if the time has passed, None never passes
+fn is_past(time: Option<SystemTime>) -> bool {
+    time.is_some_and(|time| time <= SystemTime::now())
+}
+
+/// Returns the time as seconds since the unix epoch, for the events
+pub fn to_unix(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+/// What a client needs to join a room, shared as `tsvoi://<addresses>/<room>[?invite=<token>]#<key>`
+pub struct InviteLink {
+    /// A comma separated list of server addresses
+    pub addresses: String,
+    /// Empty for the room of the server
+    pub room: String,
+    pub key: String,
+    pub token: Option<String>,
+}
+impl InviteLink {
+    /// Reads an invite link
+    /// # Errors
+    /// * The reason the link is invalid
+    pub fn parse(link: &str) -> Result<Self, String> {
+        let rest = link
+            .strip_prefix(INVITE_SCHEME)
+            .ok_or_else(|| format!("it doesn't start with {}", INVITE_SCHEME))?;
+        let (rest, key) = rest.split_once('#').ok_or("it has no key")?;
+        let (addresses, rest) = rest.split_once('/').unwrap_or((rest, ""));
+        let (room, query) = rest.split_once('?').unwrap_or((rest, ""));
+        if addresses.is_empty() {
+            return Err("it has no server address".to_string());
+        }
+        if key.is_empty() {
+            return Err("it has no key".to_string());
+        }
+        let token = match query {
+            "" => None,
+            query => Some(
+                query
+                    .strip_prefix("invite=")
+                    .filter(|token| !token.is_empty())
+                    .ok_or_else(|| format!("unknown parameter {}", query))?
+                    .to_string(),
+            ),
+        };
+        Ok(InviteLink {
+            addresses: addresses.to_string(),
+            room: Self::decode(room).ok_or("the room name is badly encoded")?,
+            key: key.to_string(),
+            token,
+        })
+    }
+
+    /// Escapes the bytes of a room name that can't be in a link
+    fn encode(room: &str) -> String {
+        room.bytes()
+            .map(|byte| match byte {
+                b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => (byte as char).to_string(),
+                _ => format!("%{:02X}", byte),
+            })
+            .collect()
+    }
+
+    fn decode(room: &str) -> Option<String> {
+        let mut bytes = Vec::with_capacity(room.len());
+        let mut chars = room.bytes();
+        while let Some(byte) = chars.next() {
+            if byte == b'%' {
+                let hex = [chars.next()?, chars.next()?];
+                bytes.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+            } else {
+                bytes.push(byte);
+            }
+        }
+        String::from_utf8(bytes).ok()
+    }
+}
+impl fmt::Display for InviteLink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}/{}", INVITE_SCHEME, self.addresses, Self::encode(&self.room))?;
+        if let Some(token) = &self.token {
+            write!(f, "?invite={}", token)?;
+        }
+        write!(f, "#{}", self.key)
+    }
+}
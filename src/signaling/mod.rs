@@ -2,33 +2,487 @@
 // SPDX-License-Identifier: GPL-3.0-only
 
 pub mod client;
+pub mod invite;
+pub mod punch;
+pub mod relay;
+pub mod room;
+pub mod sfu;
 pub mod server;
 
-use std::net::ToSocketAddrs;
-use std::net::UdpSocket;
+use bytes::{BufMut, BytesMut};
+use std::io::{Error, ErrorKind, Read, Write};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, ToSocketAddrs, UdpSocket};
+use std::sync::Mutex;
+use std::time::Duration;
 use stunclient::StunClient;
 
-pub fn get_address_ipv6() -> String {
-    let stun_addr = "stun.l.google.com:19302"
-        .to_socket_addrs()
-        .unwrap()
-        .filter(|x| x.is_ipv6())
-        .next()
-        .unwrap();
-    let udp = UdpSocket::bind("[::]:0").unwrap();
+use crate::aes::AES;
+use crate::ice::{self, Candidate};
+
+/// Identifies a peer in signaling messages, the server is 0
+pub type PeerId = u16;
+
+/// The id and username of each peer in a room
+pub type Roster = Vec<(PeerId, String)>;
+
+/// Size of the `<u8 opcode><u16 from><u16 to>` header every signaling message starts with
+pub const HEADER_LEN: usize = 5;
+
+/// Most bytes of a username, the server cuts longer usernames it's sent
+pub const MAX_USERNAME_LEN: usize = 64;
+/// Most bytes of an encrypted signaling message, a longer length means the stream is out of step.
+/// The welcome of a room with every id taken, 65535 peers of `<u16 id><u16 len>` and the longest
+/// username, takes 4.25 MiB.
+pub const MAX_MESSAGE_LEN: usize = 8 << 20;
+
+/// Encrypts a signaling message and writes it with its length
+/// # Arguments
+/// * `stream` - The signaling stream, locked so no other message is written in between
+/// * `cipher` - The room cipher
+/// * `msg` - The message
+pub fn write_message<T: AsRef<[u8]>>(stream: &mut impl Write, cipher: &AES, msg: T) -> Result<(), Error> {
+    let encrypted = cipher
+        .encrypt(msg)
+        .map_err(|_| Error::new(ErrorKind::InvalidData, "Failed to encrypt a message"))?;
+    write_frame(stream, &encrypted)
+}
+
+/// Writes an encrypted message as `<u32 len><nonce + ciphertext>`. TCP keeps no message
+/// boundaries, the length tells the reader where the message ends.
+pub fn write_frame(stream: &mut impl Write, encrypted: &[u8]) -> Result<(), Error> {
+    let mut frame = BytesMut::with_capacity(4 + encrypted.len());
+    frame.put_u32(encrypted.len() as u32);
+    frame.put(encrypted);
+    stream.write_all(&frame)
+}
+
+/// Reads one message written by `write_frame`, still encrypted
+/// # Errors
+/// * The stream failed or was closed, or the length is over MAX_MESSAGE_LEN
+pub fn read_frame(stream: &mut impl Read) -> Result<Vec<u8>, Error> {
+    let mut len = [0u8; 4];
+    stream.read_exact(&mut len)?;
+    let len = u32::from_be_bytes(len) as usize;
+    if len > MAX_MESSAGE_LEN {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("A message of {} bytes is over the limit", len),
+        ));
+    }
+    let mut encrypted = vec![0u8; len];
+    stream.read_exact(&mut encrypted)?;
+    Ok(encrypted)
+}
+
+/// Starts a signaling message
+/// # Arguments
+/// * `msg` - The empty message
+/// * `opcode` - The message type
+/// * `from` - The id of the sender
+/// * `to` - The id of the recipient
+pub fn put_header(msg: &mut BytesMut, opcode: u8, from: PeerId, to: PeerId) {
+    msg.put_u8(opcode);
+    msg.put_u16(from);
+    msg.put_u16(to);
+}
+
+/// Reads the opcode, the sender and the recipient of a decrypted signaling message
+pub fn parse_header(msg: &[u8]) -> Option<(u8, PeerId, PeerId)> {
+    if msg.len() < HEADER_LEN {
+        return None;
+    }
+    Some((msg[0], read_id(msg, 1), read_id(msg, 3)))
+}
+
+/// Reads a peer id, the message must be long enough
+/// # Arguments
+/// * `msg` - The decrypted message
+/// * `at` - The offset of the id
+pub fn read_id(msg: &[u8], at: usize) -> PeerId {
+    PeerId::from_be_bytes([msg[at], msg[at + 1]])
+}
+
+/// Cuts a username a peer announced to MAX_USERNAME_LEN bytes, on a character boundary
+pub fn limit_username(username: &str) -> String {
+    let mut end = username.len().min(MAX_USERNAME_LEN);
+    while !username.is_char_boundary(end) {
+        end -= 1;
+    }
+    username[..end].to_string()
+}
+
+/// Reads the start of an announce or acknowledge payload, `<u32 connection><u16 ip_len><ip>`
+/// # Returns
+/// * The connection id, the candidates and where the rest of the payload starts, None if the
+///   payload is cut short or the candidates aren't text
+pub fn read_announce(payload: &[u8]) -> Option<(u32, Vec<Candidate>, usize)> {
+    let connection = u32::from_be_bytes(payload.get(..4)?.try_into().ok()?);
+    let ip_len = 6 + u16::from_be_bytes(payload.get(4..6)?.try_into().ok()?) as usize;
+    let candidates = ice::parse_candidates(std::str::from_utf8(payload.get(6..ip_len)?).ok()?);
+    Some((connection, candidates, ip_len))
+}
+
+/// Appends the peers in the room as `<u16 count>` and `<u16 id><u16 username_len><username>` for each peer
+pub fn put_roster(msg: &mut BytesMut, roster: &[(PeerId, String)]) {
+    msg.put_u16(roster.len() as u16);
+    for (id, username) in roster {
+        msg.put_u16(*id);
+        msg.put_u16(username.len() as u16);
+        msg.put(username.as_bytes());
+    }
+}
+
+/// Reads the peers in the room written by `put_roster`
+/// # Returns
+/// * The id and username of each peer and the length of the roster, None if the roster is truncated
+pub fn parse_roster(msg: &[u8]) -> Option<(Roster, usize)> {
+    let count = read_id(msg.get(..2)?, 0);
+    let mut roster = Vec::with_capacity(count as usize);
+    let mut at = 2;
+    for _ in 0..count {
+        let header = msg.get(at..at + 4)?;
+        let username_len = u16::from_be_bytes([header[2], header[3]]) as usize;
+        let username = msg.get(at + 4..at + 4 + username_len)?;
+        roster.push((read_id(header, 0), String::from_utf8_lossy(username).to_string()));
+        at += 4 + username_len;
+    }
+    Some((roster, at))
+}
+
+/// Two servers on different hosts, so the NAT type can be told apart
+pub const DEFAULT_STUN_SERVERS: [&str; 2] = ["stun.l.google.com:19302", "stun1.l.google.com:19302"];
+pub const DEFAULT_STUN_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// How address candidates are discovered
+pub struct StunSettings {
+    /// STUN servers tried in order until one answers
+    pub servers: Vec<String>,
+    /// Time to wait for each server
+    pub timeout: Duration,
+    /// Skip STUN and use the local interface address
+    pub lan: bool,
+}
+
+static STUN: Mutex<StunSettings> = Mutex::new(StunSettings {
+    servers: Vec::new(),
+    timeout: DEFAULT_STUN_TIMEOUT,
+    lan: false,
+});
+
+/// Replaces the STUN servers, an empty list restores the default servers
+/// # Arguments
+/// * `servers` - host:port of each server, tried in order
+/// * `timeout` - Time to wait for each server
+pub fn set_stun_servers(servers: Vec<String>, timeout: Duration) {
+    let mut stun = STUN.lock().unwrap();
+    stun.servers = servers;
+    stun.timeout = timeout;
+}
+
+/// Enables or disables LAN mode, where local interface addresses are used instead of STUN
+pub fn set_lan_mode(lan: bool) {
+    STUN.lock().unwrap().lan = lan;
+}
+
+/// Binds a tcp listener like `TcpListener::bind`, an ipv6 listener only takes ipv6 so an ipv4
+/// listener can share its port whatever the system default is
+pub fn bind_tcp(addr: SocketAddr) -> Result<TcpListener, Error> {
+    let socket = bind_socket(addr, Type::STREAM, Protocol::TCP)?;
+    socket.listen(128)?;
+    Ok(socket.into())
+}
+
+/// Binds a udp socket like `UdpSocket::bind`, an ipv6 socket only takes ipv6 so an ipv4 socket
+/// can share its port
+pub fn bind_udp(addr: SocketAddr) -> Result<UdpSocket, Error> {
+    Ok(bind_socket(addr, Type::DGRAM, Protocol::UDP)?.into())
+}
+
+fn bind_socket(addr: SocketAddr, kind: Type, protocol: Protocol) -> Result<Socket, Error> {
+    let socket = Socket::new(Domain::for_address(addr), kind, Some(protocol))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    //Like the std listeners, so a restarted server gets its port back right away
+    #[cfg(unix)]
+    if kind == Type::STREAM {
+        socket.set_reuse_address(true)?;
+    }
+    socket.bind(&addr.into())?;
+    Ok(socket)
+}
+
+/// Separates the candidates of a peer in announce and acknowledge messages
+pub const CANDIDATE_SEPARATOR: char = ',';
+
+/// Gathers an address candidate for each address family available on this machine, ipv6 first
+pub fn get_candidates() -> Vec<String> {
+    let mut candidates = Vec::new();
+    for (ipv6, candidate) in [(true, get_address_ipv6()), (false, get_address_ipv4())] {
+        match candidate {
+            Ok(candidate) => candidates.push(candidate),
+            Err(e) => debug!("No {} candidate: {}", family(ipv6), e),
+        }
+    }
+    candidates
+}
+
+/// Parses the candidate list of an announce or acknowledge message
+pub fn split_candidates(candidates: &str) -> Vec<String> {
+    candidates
+        .split(CANDIDATE_SEPARATOR)
+        .filter(|candidate| !candidate.is_empty())
+        .map(|candidate| candidate.to_string())
+        .collect()
+}
+
+/// Serializes candidates for an announce or acknowledge message
+pub fn join_candidates(candidates: &[String]) -> String {
+    candidates.join(&CANDIDATE_SEPARATOR.to_string())
+}
+
+pub fn get_address_ipv6() -> Result<String, Error> {
+    get_address(true)
+}
+pub fn get_address_ipv4() -> Result<String, Error> {
+    get_address(false)
+}
+
+/// Returns an address candidate with a free port, falls back to the local
+/// interface address if no STUN server answers
+fn get_address(ipv6: bool) -> Result<String, Error> {
+    let udp = if ipv6 {
+        UdpSocket::bind("[::]:0")?
+    } else {
+        UdpSocket::bind("0.0.0.0:0")?
+    };
+    if let Some(mapped) = get_mapped_address(&udp, ipv6) {
+        return Ok(mapped.to_string());
+    }
+    Ok(get_local_address(&udp, ipv6)?.to_string())
+}
+
+/// Asks the STUN servers, in order, which public address the socket is seen from
+/// # Returns
+/// * `None` - In LAN mode or if no server answers
+pub fn get_mapped_address(udp: &UdpSocket, ipv6: bool) -> Option<SocketAddr> {
+    let (servers, timeout, lan) = get_stun_settings();
+    if lan {
+        return None;
+    }
+    for server in servers.iter() {
+        if let Some(mapped) = query_stun(udp, server, ipv6, timeout) {
+            return Some(mapped);
+        }
+    }
+    warn!("No STUN server answered, using the local {} address", family(ipv6));
+    None
+}
+
+/// Asks a single STUN server which public address the socket is seen from
+fn query_stun(udp: &UdpSocket, server: &str, ipv6: bool, timeout: Duration) -> Option<SocketAddr> {
+    let stun_addr = match resolve(server, ipv6) {
+        Some(addr) => addr,
+        None => {
+            debug!("STUN server {} has no {} address", server, family(ipv6));
+            return None;
+        }
+    };
     let mut c = StunClient::new(stun_addr);
     c.set_software(Some("tSVoI"));
-    c.query_external_address(&udp).unwrap().to_string()
+    c.set_timeout(timeout);
+    let mapped = c.query_external_address(udp);
+    //The socket may carry audio afterwards
+    let _ = udp.set_read_timeout(None);
+    match mapped {
+        Ok(addr) => Some(addr),
+        Err(e) => {
+            debug!("STUN server {} failed: {}", server, e);
+            None
+        }
+    }
+}
+
+/// How a NAT maps a socket to public addresses
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum NatType {
+    /// The socket is reachable at its local address
+    Open,
+    /// The same public address for every destination, hole punching works
+    EndpointIndependent,
+    /// A new public address for every destination, only a relay works
+    Symmetric,
+    /// LAN mode, or fewer than two STUN servers answered
+    Unknown,
 }
-pub fn get_address_ipv4() -> String {
-    let stun_addr = "stun.l.google.com:19302"
+impl NatType {
+    pub fn to_text(self) -> &'static str {
+        match self {
+            NatType::Open => "open",
+            NatType::EndpointIndependent => "cone",
+            NatType::Symmetric => "symmetric",
+            NatType::Unknown => "unknown",
+        }
+    }
+}
+
+/// Compares the addresses two STUN servers on different hosts see the same socket from
+pub fn get_nat_type(ipv6: bool) -> NatType {
+    let (servers, timeout, lan) = get_stun_settings();
+    if lan {
+        return NatType::Unknown;
+    }
+    let udp = match UdpSocket::bind(if ipv6 { "[::]:0" } else { "0.0.0.0:0" }) {
+        Ok(udp) => udp,
+        Err(_) => return NatType::Unknown,
+    };
+    let mut queried: Vec<IpAddr> = Vec::new();
+    let mut mapped: Vec<SocketAddr> = Vec::new();
+    for server in servers.iter() {
+        //Two names of the same host tell nothing
+        match resolve(server, ipv6) {
+            Some(addr) if !queried.contains(&addr.ip()) => queried.push(addr.ip()),
+            _ => continue,
+        }
+        if let Some(addr) = query_stun(&udp, server, ipv6, timeout) {
+            mapped.push(addr);
+        }
+        if mapped.len() == 2 {
+            break;
+        }
+    }
+    let local = get_local_address(&udp, ipv6).ok();
+    match mapped.as_slice() {
+        [first, ..] if Some(*first) == local => NatType::Open,
+        [first, second] if first == second => NatType::EndpointIndependent,
+        [_, _] => NatType::Symmetric,
+        _ => NatType::Unknown,
+    }
+}
+
+/// Detects the NAT type of each address family and prints it
+pub fn print_nat_types() {
+    for ipv6 in [true, false] {
+        println!(
+            "{{ \"event_code\": 12, \"family\": \"{}\", \"nat\": \"{}\" }}",
+            family(ipv6),
+            get_nat_type(ipv6).to_text()
+        );
+    }
+}
+
+/// Finds the address of the interface that routes to the outside, or of any interface on a
+/// network without a route (or the loopback address on an isolated machine), and pairs it with
+/// the socket's port
+pub fn get_local_address(udp: &UdpSocket, ipv6: bool) -> Result<SocketAddr, Error> {
+    let (servers, _, lan) = get_stun_settings();
+    let port = udp.local_addr()?.port();
+    //Connecting a udp socket sends nothing, it only picks the outgoing interface
+    let mut probes: Vec<SocketAddr> = if lan {
+        Vec::new()
+    } else {
+        servers
+            .iter()
+            .filter_map(|server| resolve(server, ipv6))
+            .collect()
+    };
+    probes.push(if ipv6 {
+        SocketAddr::new(IpAddr::V6(Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 1)), 9)
+    } else {
+        SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 255, 255, 255)), 9)
+    });
+    let probe_socket = if ipv6 {
+        UdpSocket::bind("[::]:0")?
+    } else {
+        UdpSocket::bind("0.0.0.0:0")?
+    };
+    for probe in probes {
+        if probe_socket.connect(probe).is_ok() {
+            let ip = probe_socket.local_addr()?.ip();
+            if !ip.is_unspecified() {
+                return Ok(SocketAddr::new(ip, port));
+            }
+        }
+    }
+    if let Some(ip) = get_interface_addresses(ipv6).first() {
+        return Ok(SocketAddr::new(*ip, port));
+    }
+    let loopback = if ipv6 {
+        IpAddr::V6(Ipv6Addr::LOCALHOST)
+    } else {
+        IpAddr::V4(Ipv4Addr::LOCALHOST)
+    };
+    Ok(SocketAddr::new(loopback, port))
+}
+
+/// Lists the addresses of the interfaces that are up, without the loopback and the ipv6
+/// link-local addresses, which other hosts can't reach without a scope
+#[cfg(unix)]
+pub fn get_interface_addresses(ipv6: bool) -> Vec<IpAddr> {
+    let mut addresses = Vec::new();
+    let mut ifaddrs: *mut libc::ifaddrs = std::ptr::null_mut();
+    //SAFETY: getifaddrs fills a list that stays valid until freeifaddrs, each address is read as
+    //the sockaddr type of its family
+    unsafe {
+        if libc::getifaddrs(&mut ifaddrs) != 0 {
+            return addresses;
+        }
+        let mut next = ifaddrs;
+        while let Some(ifaddr) = next.as_ref() {
+            next = ifaddr.ifa_next;
+            if ifaddr.ifa_addr.is_null() || ifaddr.ifa_flags & libc::IFF_UP as libc::c_uint == 0 {
+                continue;
+            }
+            let ip = match (*ifaddr.ifa_addr).sa_family as libc::c_int {
+                libc::AF_INET if !ipv6 => {
+                    let addr = &*(ifaddr.ifa_addr as *const libc::sockaddr_in);
+                    IpAddr::V4(Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr)))
+                }
+                libc::AF_INET6 if ipv6 => {
+                    let addr = &*(ifaddr.ifa_addr as *const libc::sockaddr_in6);
+                    IpAddr::V6(Ipv6Addr::from(addr.sin6_addr.s6_addr))
+                }
+                _ => continue,
+            };
+            let link_local = matches!(ip, IpAddr::V6(ip) if ip.segments()[0] & 0xffc0 == 0xfe80);
+            if !ip.is_loopback() && !ip.is_unspecified() && !link_local && !addresses.contains(&ip) {
+                addresses.push(ip);
+            }
+        }
+        libc::freeifaddrs(ifaddrs);
+    }
+    addresses
+}
+
+/// Only the interface that routes to the outside is found on the other systems
+#[cfg(not(unix))]
+pub fn get_interface_addresses(_ipv6: bool) -> Vec<IpAddr> {
+    Vec::new()
+}
+
+/// Returns the STUN servers (the default one if none was set), the timeout and the LAN mode
+fn get_stun_settings() -> (Vec<String>, Duration, bool) {
+    let stun = STUN.lock().unwrap();
+    let servers = if stun.servers.is_empty() {
+        DEFAULT_STUN_SERVERS.iter().map(|server| server.to_string()).collect()
+    } else {
+        stun.servers.clone()
+    };
+    (servers, stun.timeout, stun.lan)
+}
+
+fn resolve(server: &str, ipv6: bool) -> Option<SocketAddr> {
+    server
         .to_socket_addrs()
-        .unwrap()
-        .filter(|x| x.is_ipv4())
-        .next()
-        .unwrap();
-    let udp = UdpSocket::bind("[::]:0").unwrap();
-    let mut c = StunClient::new(stun_addr);
-    c.set_software(Some("tSVoI"));
-    c.query_external_address(&udp).unwrap().to_string()
+        .ok()?
+        .find(|addr| addr.is_ipv6() == ipv6)
+}
+
+fn family(ipv6: bool) -> &'static str {
+    if ipv6 {
+        "ipv6"
+    } else {
+        "ipv4"
+    }
 }
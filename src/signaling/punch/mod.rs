@@ -0,0 +1,158 @@
+// SPDX-FileCopyrightText: Copyright 2023 tSVoI
+// SPDX-License-Identifier: GPL-3.0-only
+
+use bytes::{BufMut, BytesMut};
+use std::collections::HashMap;
+use std::net::TcpStream;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::aes::AES;
+use crate::audio_peer::AudioPeer;
+use crate::signaling::{self, PeerId};
+use crate::signaling::relay::Relay;
+
+/// Time between the punch message and the checks, long enough for the message to reach both peers
+pub const PUNCH_DELAY: Duration = Duration::from_millis(500);
+/// Rounds of checks before a pair of peers gives up
+pub const PUNCH_ATTEMPTS: u8 = 3;
+/// Marks a pair that gave up
+const GAVE_UP: u8 = u8::MAX;
+
+/// Tells both peers of a pair to run their connectivity checks at the same time,
+/// so each NAT has an outgoing mapping when the other peer's requests arrive.
+/// The relay is offered with each round as the lowest priority candidate.
+#[derive(Clone)]
+pub struct PunchCoordinator {
+    streams: Arc<Mutex<HashMap<PeerId, TcpStream>>>,
+    audio_peers: Arc<Mutex<HashMap<PeerId, AudioPeer>>>,
+    aes: Arc<AES>,
+    relay: Arc<Relay>,
+    /// The current round of each pair, keyed by (lower id, higher id)
+    attempts: Arc<Mutex<HashMap<(PeerId, PeerId), u8>>>,
+    /// The relay token of each side of a pair, keyed by (owner id, other id)
+    tokens: Arc<Mutex<HashMap<(PeerId, PeerId), u64>>>,
+}
+impl PunchCoordinator {
+    /// Creates a new PunchCoordinator for the server
+    /// # Arguments
+    /// * `streams` - The signaling streams of the clients
+    /// * `audio_peers` - The audio peers of the server, id 0
+    /// * `aes` - The server cipher
+    /// * `relay` - The relay pairs fall back to
+    pub fn new(
+        streams: Arc<Mutex<HashMap<PeerId, TcpStream>>>,
+        audio_peers: Arc<Mutex<HashMap<PeerId, AudioPeer>>>,
+        aes: Arc<AES>,
+        relay: Arc<Relay>,
+    ) -> Self {
+        PunchCoordinator {
+            streams,
+            audio_peers,
+            aes,
+            relay,
+            attempts: Arc::new(Mutex::new(HashMap::new())),
+            tokens: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Starts a round of checks between two peers
+    /// # Arguments
+    /// * `a` - The id of a peer
+    /// * `b` - The id of the other peer
+    /// * `attempt` - The round, starting at 0
+    pub fn schedule(&self, a: PeerId, b: PeerId, attempt: u8) {
+        debug!("Punch round {} between {} and {}", attempt, a, b);
+        self.attempts
+            .lock()
+            .unwrap()
+            .insert((a.min(b), a.max(b)), attempt);
+        let tokens = self.allocate(a, b);
+        for (me, other) in [(a, b), (b, a)] {
+            let token = tokens.map(|(token_a, token_b)| if me == a { token_a } else { token_b });
+            if me == 0 {
+                let peers = self.audio_peers.lock().unwrap();
+                if let Some(peer) = peers.get(&other) {
+                    if let Some(token) = token {
+                        peer.add_relay(&self.relay.get_local_addresses(), token);
+                    }
+                    let coordinator = self.clone();
+                    peer.punch(PUNCH_DELAY, attempt, move |attempt| {
+                        coordinator.on_failed(0, other, attempt)
+                    });
+                }
+                continue;
+            }
+            let mut punch_msg = BytesMut::with_capacity(1024);
+            signaling::put_header(&mut punch_msg, 5, 0, me);
+            punch_msg.put_u16(other);
+            punch_msg.put_u8(attempt);
+            punch_msg.put_u16(PUNCH_DELAY.as_millis() as u16);
+            if let Some(token) = token {
+                let relays: Vec<String> = self
+                    .relay
+                    .get_addresses()
+                    .iter()
+                    .map(|relay| relay.to_string())
+                    .collect();
+                punch_msg.put_u64(token);
+                punch_msg.put(signaling::join_candidates(&relays).as_bytes());
+            }
+            if let Some(stream) = self.streams.lock().unwrap().get_mut(&me) {
+                let _ = signaling::write_message(stream, &self.aes, punch_msg);
+            }
+        }
+    }
+
+    /// Starts the next round when a peer reports that a round failed, or gives up on
+    /// the pair after the last round. Both peers of the pair report the failure,
+    /// the second report is ignored.
+    /// # Arguments
+    /// * `reporter` - The id of the peer whose checks failed
+    /// * `other` - The id of the peer it tried to reach
+    /// * `attempt` - The round that failed
+    pub fn on_failed(&self, reporter: PeerId, other: PeerId, attempt: u8) {
+        let key = (reporter.min(other), reporter.max(other));
+        let mut attempts = self.attempts.lock().unwrap();
+        if attempts.get(&key) != Some(&attempt) {
+            return;
+        }
+        if attempt + 1 < PUNCH_ATTEMPTS {
+            drop(attempts);
+            self.schedule(reporter, other, attempt + 1);
+            return;
+        }
+        attempts.insert(key, GAVE_UP);
+        info!("Peers {} and {} can't reach each other", reporter, other);
+    }
+
+    /// Creates the relayed path of a pair on its first round, later rounds reuse it
+    /// # Returns
+    /// * The token of each peer, in the same order as the arguments, None if the relay is off
+    fn allocate(&self, a: PeerId, b: PeerId) -> Option<(u64, u64)> {
+        if !self.relay.is_enabled() {
+            return None;
+        }
+        let mut tokens = self.tokens.lock().unwrap();
+        if let (Some(token_a), Some(token_b)) = (tokens.get(&(a, b)), tokens.get(&(b, a))) {
+            return Some((*token_a, *token_b));
+        }
+        let (token_a, token_b) = self.relay.allocate(a, b);
+        tokens.insert((a, b), token_a);
+        tokens.insert((b, a), token_b);
+        Some((token_a, token_b))
+    }
+
+    /// Forgets the rounds and the relayed paths of a peer that left
+    pub fn remove_peer(&self, id: PeerId) {
+        self.attempts
+            .lock()
+            .unwrap()
+            .retain(|(a, b), _| *a != id && *b != id);
+        self.tokens
+            .lock()
+            .unwrap()
+            .retain(|(a, b), _| *a != id && *b != id);
+        self.relay.release(id);
+    }
+}
@@ -0,0 +1,306 @@
+// SPDX-FileCopyrightText: Copyright 2023 tSVoI
+// SPDX-License-Identifier: GPL-3.0-only
+
+use aead::rand_core::RngCore;
+use aead::OsRng;
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, UdpSocket};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Instant;
+
+use crate::signaling::sfu::Sfu;
+use crate::signaling::{self, PeerId};
+use crate::spawn_thread;
+
+/// Default bandwidth each relayed peer may send, enough for a 64 kbit/s opus stream and its overhead
+pub const DEFAULT_RELAY_BANDWIDTH: u32 = 256;
+/// Most bandwidth a relayed peer can be given, its budget of bytes per second still fits a u32
+pub const MAX_RELAY_BANDWIDTH: u32 = u32::MAX / 125;
+
+/// One side of a relayed pair, identified by the token its packets start with
+struct Allocation {
+    owner: PeerId,
+    /// The token of the other side
+    peer_token: u64,
+    /// Learned from the last packet of the owner
+    addr: Option<SocketAddr>,
+    /// Bytes the owner may still send in the current second
+    budget: u32,
+    budget_start: Instant,
+}
+
+/// Forwards udp packets between peers that can't reach each other, and the voice of the
+/// clients of forwarding rooms. Packets are `<u64 token><encrypted audio peer packet>`,
+/// the relay only reads the token and the connection id behind it.
+pub struct Relay {
+    /// A socket per address family, with the local and the advertised address
+    sockets: Vec<(UdpSocket, SocketAddr, SocketAddr)>,
+    allocations: Arc<Mutex<HashMap<u64, Allocation>>>,
+    sfu: Arc<Sfu>,
+    /// kbit/s each relayed peer may send
+    bandwidth: Arc<AtomicU32>,
+    /// False if pairs that can't punch through are left without audio
+    enabled: AtomicBool,
+}
+impl Relay {
+    /// Binds a udp socket next to each signaling listener, on the same port if it's free
+    /// # Arguments
+    /// * `listeners` - The signaling listeners and their advertised addresses
+    pub fn new(listeners: &[(TcpListener, String)]) -> Self {
+        let mut sockets = Vec::new();
+        for (listener, advertised) in listeners {
+            let (local, advertised) = match (listener.local_addr(), advertised.parse::<SocketAddr>()) {
+                (Ok(local), Ok(advertised)) => (local, advertised),
+                _ => continue,
+            };
+            let socket = match signaling::bind_udp(local)
+                .or_else(|_| signaling::bind_udp(SocketAddr::new(local.ip(), 0)))
+            {
+                Ok(socket) => socket,
+                Err(e) => {
+                    error!("Couldn't bind the relay next to {}: {}", local, e);
+                    continue;
+                }
+            };
+            let port = match socket.local_addr() {
+                Ok(addr) => addr.port(),
+                Err(_) => continue,
+            };
+            //Peers on this machine reach the relay through the loopback address
+            let local_ip = match local.ip() {
+                IpAddr::V4(ip) if ip.is_unspecified() => IpAddr::V4(Ipv4Addr::LOCALHOST),
+                IpAddr::V6(ip) if ip.is_unspecified() => IpAddr::V6(Ipv6Addr::LOCALHOST),
+                ip => ip,
+            };
+            sockets.push((
+                socket,
+                SocketAddr::new(local_ip, port),
+                SocketAddr::new(advertised.ip(), port),
+            ));
+        }
+        Relay {
+            sockets,
+            allocations: Arc::new(Mutex::new(HashMap::new())),
+            sfu: Arc::new(Sfu::new()),
+            bandwidth: Arc::new(AtomicU32::new(DEFAULT_RELAY_BANDWIDTH)),
+            enabled: AtomicBool::new(true),
+        }
+    }
+
+    /// Starts forwarding packets
+    pub fn start(&self) {
+        if !self.is_enabled() {
+            return;
+        }
+        let sockets: Vec<UdpSocket> = self
+            .sockets
+            .iter()
+            .filter_map(|(socket, _, _)| socket.try_clone().ok())
+            .collect();
+        for (socket, local, _) in self.sockets.iter() {
+            let socket = match socket.try_clone() {
+                Ok(socket) => socket,
+                Err(_) => continue,
+            };
+            let sockets: Vec<UdpSocket> = sockets
+                .iter()
+                .filter_map(|socket| socket.try_clone().ok())
+                .collect();
+            let allocations = self.allocations.clone();
+            let sfu = self.sfu.clone();
+            let bandwidth = self.bandwidth.clone();
+            spawn_thread!(format!("relay {local}"), move || {
+                let recv_buffer = &mut [0u8; 2048];
+                loop {
+                    let (n, source) = match socket.recv_from(recv_buffer) {
+                        Ok(received) => received,
+                        Err(e) => {
+                            error!("Relay stopped: {}", e);
+                            return;
+                        }
+                    };
+                    if n < 8 {
+                        continue;
+                    }
+                    let mut token = [0u8; 8];
+                    token.copy_from_slice(&recv_buffer[..8]);
+                    let token = u64::from_be_bytes(token);
+                    let forwarded = sfu.route(token, source, &recv_buffer[8..n], bandwidth.load(Ordering::Relaxed));
+                    if let Some(forwarded) = forwarded {
+                        for (destination, packet) in forwarded {
+                            if let Some(out) = Self::socket_for(&sockets, &destination) {
+                                let _ = out.send_to(&packet, destination);
+                            }
+                        }
+                        continue;
+                    }
+                    let destination = Self::route(
+                        &allocations,
+                        token,
+                        source,
+                        (n - 8) as u32,
+                        bandwidth.load(Ordering::Relaxed),
+                    );
+                    //A bare token only refreshes the owner's address
+                    if n == 8 {
+                        continue;
+                    }
+                    let destination = match destination {
+                        Some(destination) => destination,
+                        None => continue,
+                    };
+                    if let Some(out) = Self::socket_for(&sockets, &destination) {
+                        let _ = out.send_to(&recv_buffer[8..n], destination);
+                    }
+                }
+            });
+        }
+    }
+
+    /// Returns the socket of the same family as the destination
+    fn socket_for<'a>(sockets: &'a [UdpSocket], destination: &SocketAddr) -> Option<&'a UdpSocket> {
+        sockets.iter().find(|socket| {
+            socket
+                .local_addr()
+                .is_ok_and(|addr| addr.is_ipv6() == destination.is_ipv6())
+        })
+    }
+
+    /// Returns the bytes a sender may send each second, capped at u32::MAX for the huge bandwidths
+    /// # Arguments
+    /// * `bandwidth` - kbit/s the sender may use
+    fn budget(bandwidth: u32) -> u32 {
+        (bandwidth as u64 * 1000 / 8).min(u32::MAX as u64) as u32
+    }
+
+    /// Takes a packet from the budget of its sender, the budget is refilled every second
+    /// # Arguments
+    /// * `budget` - Bytes the sender may still send in the current second
+    /// * `budget_start` - When the current second started
+    /// * `len` - The size of the packet
+    /// * `bandwidth` - kbit/s the sender may use, 0 for no limit
+    /// # Returns
+    /// * False if the packet is over the budget and must be dropped
+    pub fn spend(budget: &mut u32, budget_start: &mut Instant, len: u32, bandwidth: u32) -> bool {
+        if bandwidth == 0 {
+            return true;
+        }
+        if budget_start.elapsed().as_secs() >= 1 {
+            *budget = Self::budget(bandwidth);
+            *budget_start = Instant::now();
+        }
+        if *budget < len {
+            return false;
+        }
+        *budget -= len;
+        true
+    }
+
+    /// Learns the address of the sender and returns where its packet goes
+    /// # Arguments
+    /// * `token` - The token the packet starts with
+    /// * `source` - The address the packet came from
+    /// * `len` - The size of the relayed part
+    /// * `bandwidth` - kbit/s the sender may use, 0 for no limit
+    fn route(
+        allocations: &Mutex<HashMap<u64, Allocation>>,
+        token: u64,
+        source: SocketAddr,
+        len: u32,
+        bandwidth: u32,
+    ) -> Option<SocketAddr> {
+        let mut allocations = allocations.lock().unwrap();
+        let allocation = allocations.get_mut(&token)?;
+        allocation.addr = Some(source);
+        if !Self::spend(&mut allocation.budget, &mut allocation.budget_start, len, bandwidth) {
+            debug!("Peer {} is over the relay bandwidth, dropping a packet", allocation.owner);
+            return None;
+        }
+        let peer_token = allocation.peer_token;
+        allocations.get(&peer_token)?.addr
+    }
+
+    /// Creates a relayed path between two peers
+    /// # Returns
+    /// * The token of each peer, in the same order as the arguments
+    pub fn allocate(&self, a: PeerId, b: PeerId) -> (u64, u64) {
+        let token_a = OsRng.next_u64();
+        let token_b = OsRng.next_u64();
+        let budget = Self::budget(self.bandwidth.load(Ordering::Relaxed));
+        let mut allocations = self.allocations.lock().unwrap();
+        for (owner, token, peer_token) in [(a, token_a, token_b), (b, token_b, token_a)] {
+            allocations.insert(
+                token,
+                Allocation {
+                    owner,
+                    peer_token,
+                    addr: None,
+                    budget,
+                    budget_start: Instant::now(),
+                },
+            );
+        }
+        debug!("Relaying between {} and {}", a, b);
+        (token_a, token_b)
+    }
+
+    /// Adds a client to a forwarding room, its voice is copied to the other clients of the room
+    /// # Returns
+    /// * The token the client puts in front of its packets
+    pub fn forward(&self, room: &str, id: PeerId) -> u64 {
+        self.sfu.join(room, id, Self::budget(self.bandwidth.load(Ordering::Relaxed)))
+    }
+
+    /// Records the connection id a client of a forwarding room picked for the packets of another client
+    pub fn set_connection(&self, receiver: PeerId, sender: PeerId, connection: u32) {
+        self.sfu.set_connection(receiver, sender, connection);
+    }
+
+    /// Limits how many clients of each forwarding room are forwarded at the same time
+    /// # Arguments
+    /// * `max_speakers` - Most senders at the same time, 0 for no limit
+    pub fn set_max_speakers(&self, max_speakers: u16) {
+        self.sfu.set_max_speakers(max_speakers);
+    }
+
+    /// Drops every relayed path of a peer
+    pub fn release(&self, id: PeerId) {
+        self.sfu.release(id);
+        let mut allocations = self.allocations.lock().unwrap();
+        //The other side of each pair goes too
+        let peer_tokens: Vec<u64> = allocations
+            .values()
+            .filter(|allocation| allocation.owner == id)
+            .map(|allocation| allocation.peer_token)
+            .collect();
+        allocations.retain(|token, allocation| allocation.owner != id && !peer_tokens.contains(token));
+    }
+
+    /// Changes the bandwidth each relayed peer may send
+    /// # Arguments
+    /// * `kbps` - kbit/s, 0 for no limit
+    pub fn set_bandwidth(&self, kbps: u32) {
+        self.bandwidth.store(kbps, Ordering::Relaxed);
+    }
+
+    /// Turns the relay on or off, only before it's started
+    pub fn set_enabled(&self, enabled: bool) {
+        self.enabled.store(enabled, Ordering::Relaxed);
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    /// Returns the addresses peers on other machines send to, ipv6 first
+    pub fn get_addresses(&self) -> Vec<SocketAddr> {
+        self.sockets.iter().map(|(_, _, advertised)| *advertised).collect()
+    }
+
+    /// Returns the addresses the server's own peers send to
+    pub fn get_local_addresses(&self) -> Vec<SocketAddr> {
+        self.sockets.iter().map(|(_, local, _)| *local).collect()
+    }
+}
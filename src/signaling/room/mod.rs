@@ -0,0 +1,167 @@
+// SPDX-FileCopyrightText: Copyright 2023 tSVoI
+// SPDX-License-Identifier: GPL-3.0-only
+
+use bytes::{BufMut, Bytes, BytesMut};
+use std::collections::HashMap;
+use std::net::TcpStream;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::aes::{KeyError, AES};
+use crate::audio_peer::AudioPeer;
+use crate::signaling::invite::INVITE_FLAG;
+use crate::signaling::punch::PunchCoordinator;
+use crate::signaling::relay::Relay;
+use crate::signaling::{self, PeerId, HEADER_LEN};
+
+/// The room clients that don't name a room join, the server talks in it unless it's headless
+pub const HOSTED_ROOM: &str = "";
+/// Longest room name a client can send, the top bit of its length flags an invite
+pub const MAX_ROOM_NAME: usize = (INVITE_FLAG - 1) as usize;
+
+/// A group of peers that mesh with each other, with its own key.
+/// Peers of different rooms never see each other's messages.
+pub struct Room {
+    pub name: String,
+    pub cipher: Arc<AES>,
+    pub streams: Arc<Mutex<HashMap<PeerId, TcpStream>>>,
+    /// The usernames the clients announced to the server
+    pub usernames: Arc<Mutex<HashMap<PeerId, String>>>,
+    pub punch: PunchCoordinator,
+    relay: Arc<Relay>,
+    /// True for the room the server is peer 0 of
+    pub hosted: bool,
+    /// True if the server forwards the voice of the room instead of the peers meshing
+    pub forwarding: bool,
+}
+impl Room {
+    /// Creates an empty room
+    /// # Arguments
+    /// * `name` - The name clients join the room by
+    /// * `key` - The room key, a new key is generated if None
+    /// * `audio_peers` - The audio peers of the server, only used if the server is in the room
+    /// * `relay` - The relay pairs of this room fall back to
+    /// * `hosted` - True if the server is peer 0 of the room
+    pub fn new(
+        name: &str,
+        key: Option<&str>,
+        audio_peers: Arc<Mutex<HashMap<PeerId, AudioPeer>>>,
+        relay: Arc<Relay>,
+        hosted: bool,
+    ) -> Result<Self, KeyError> {
+        let cipher = Arc::new(AES::new(key)?);
+        let streams = Arc::new(Mutex::new(HashMap::new()));
+        let punch = PunchCoordinator::new(streams.clone(), audio_peers, cipher.clone(), relay.clone());
+        Ok(Room {
+            name: name.to_string(),
+            cipher,
+            streams,
+            usernames: Arc::new(Mutex::new(HashMap::new())),
+            punch,
+            relay,
+            hosted,
+            forwarding: false,
+        })
+    }
+
+    /// Returns true if no other client can join
+    /// # Arguments
+    /// * `streams` - The locked streams of the room
+    /// * `max_peers` - Most peers in the room, the server included if it's in the room, 0 for no limit
+    pub fn is_full(&self, streams: &HashMap<PeerId, TcpStream>, max_peers: u16) -> bool {
+        max_peers != 0 && streams.len() + self.hosted as usize >= max_peers as usize
+    }
+
+    /// Builds the message that gives a client its id, with the roster of the peers already in
+    /// the room so the client only announces to peers that are connected, and the token that
+    /// resumes the id after a reconnection. A client of a forwarding room also gets the token
+    /// and the addresses it sends its voice to.
+    /// # Arguments
+    /// * `id` - The id of the client
+    /// * `resume_token` - The token handed out with the id
+    /// * `streams` - The locked streams of the room
+    /// * `usernames` - The usernames the clients announced, a client that didn't announce yet has none
+    /// * `my_username` - The username of the server, listed if the server is in the room
+    pub fn welcome(
+        &self,
+        id: PeerId,
+        resume_token: u64,
+        streams: &HashMap<PeerId, TcpStream>,
+        usernames: &HashMap<PeerId, String>,
+        my_username: &str,
+    ) -> Bytes {
+        let mut roster = Vec::new();
+        if self.hosted {
+            roster.push((0, my_username.to_string()));
+        }
+        roster.extend(
+            streams
+                .keys()
+                .filter(|&&sid| sid != id)
+                .map(|sid| (*sid, usernames.get(sid).cloned().unwrap_or_default())),
+        );
+        let mut welcome_msg = BytesMut::with_capacity(1024);
+        welcome_msg.put_u8(0);
+        welcome_msg.put_u16(id);
+        signaling::put_roster(&mut welcome_msg, &roster);
+        welcome_msg.put_u64(resume_token);
+        if self.forwarding {
+            let relays: Vec<String> = self
+                .relay
+                .get_addresses()
+                .iter()
+                .map(|relay| relay.to_string())
+                .collect();
+            welcome_msg.put_u64(self.relay.forward(&self.name, id));
+            welcome_msg.put(signaling::join_candidates(&relays).as_bytes());
+        }
+        welcome_msg.freeze()
+    }
+
+    /// Sends the mute and deafen state of a peer to every other client of the room
+    /// # Arguments
+    /// * `from` - The id of the peer whose state changed
+    /// * `flags` - Its presence flags
+    pub fn broadcast_presence(&self, from: PeerId, flags: u8) {
+        let mut streams = self.streams.lock().unwrap();
+        for (sid, stream) in streams.iter_mut().filter(|(sid, _)| **sid != from) {
+            let mut presence_msg = BytesMut::with_capacity(HEADER_LEN + 1);
+            signaling::put_header(&mut presence_msg, 12, from, *sid);
+            presence_msg.put_u8(flags);
+            let _ = signaling::write_message(stream, &self.cipher, presence_msg);
+        }
+    }
+
+    /// Sends a heartbeat to every client of the room, each client answers so its stream never
+    /// stays silent for longer than the timeout unless the connection is dead
+    pub fn send_heartbeats(&self, timeout: Duration) {
+        let mut streams = self.streams.lock().unwrap();
+        for (sid, stream) in streams.iter_mut() {
+            let mut heartbeat_msg = BytesMut::with_capacity(HEADER_LEN + 4);
+            signaling::put_header(&mut heartbeat_msg, 9, 0, *sid);
+            heartbeat_msg.put_u32(timeout.as_millis() as u32);
+            if let Err(e) = signaling::write_message(stream, &self.cipher, heartbeat_msg) {
+                debug!("Failed to send a heartbeat to {}: {}", sid, e);
+            }
+        }
+    }
+
+    /// Records the connection id a client picked for the packets of another client, the server
+    /// puts it in front of the voice it forwards
+    pub fn set_connection(&self, receiver: PeerId, sender: PeerId, connection: u32) {
+        self.relay.set_connection(receiver, sender, connection);
+    }
+
+    /// Forgets a client that left and tells the rest of the room
+    pub fn remove(&self, id: PeerId) {
+        self.streams.lock().unwrap().remove(&id);
+        self.usernames.lock().unwrap().remove(&id);
+        self.punch.remove_peer(id);
+        self.streams.lock().unwrap().iter_mut().for_each(|(sid, stream)| {
+            let mut reply = BytesMut::with_capacity(HEADER_LEN + 2);
+            signaling::put_header(&mut reply, 4, 0, *sid);
+            reply.put_u16(id);
+            let _ = signaling::write_message(stream, &self.cipher, reply);
+        });
+    }
+}
@@ -1,117 +1,506 @@
 // SPDX-FileCopyrightText: Copyright 2023 tSVoI
 // SPDX-License-Identifier: GPL-3.0-only
 
+use aead::rand_core::RngCore;
+use aead::OsRng;
 use bytes::{BufMut, Bytes, BytesMut};
-use std::collections::HashMap;
-use std::io::{Read, Write};
-use std::net::TcpListener;
-use std::net::TcpStream;
-use std::sync::atomic::AtomicU8;
+use std::collections::{HashMap, HashSet};
+use std::io::Read;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream};
 use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::{Duration, SystemTime};
 
-use crate::aes::AES;
 use crate::audio::playback;
+use crate::audio::device::DeviceSelection;
 use crate::audio_peer::AudioPeer;
-use crate::signaling;
+use crate::ice;
+use crate::roster;
+use crate::signaling::{self, PeerId, HEADER_LEN, MAX_USERNAME_LEN};
+use crate::signaling::invite::{InviteLink, Invites, PresentedInvite, Refusal, INVITE_FLAG};
+use crate::signaling::relay::Relay;
+use crate::signaling::room::{Room, HOSTED_ROOM, MAX_ROOM_NAME};
 use crate::spawn_thread;
 
+/// Time between two heartbeats to each client
+pub const DEFAULT_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
+/// Time without any message from a client before it's evicted
+pub const DEFAULT_HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Time a new connection has to name its room
+const HELLO_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The ids in use on the server. Ids are unique across the rooms so the relay and the
+/// punch rounds never mix up peers of different rooms.
+struct PeerIds {
+    /// The id given to the next client
+    next: PeerId,
+    taken: HashSet<PeerId>,
+    /// The token handed out with each id, kept after the client left so only that client can
+    /// resume the id, replaced when the id is given to another client
+    resume_tokens: HashMap<PeerId, u64>,
+}
+impl PeerIds {
+    /// Picks the id of a new client. Ids are handed out in turn and reused once their client
+    /// left, so a client that reconnects soon after losing its connection likely gets its id back.
+    /// # Returns
+    /// * The id and the token the client resumes it with, None if every id is taken
+    fn allocate(&mut self) -> Option<(PeerId, u64)> {
+        for _ in 0..PeerId::MAX {
+            let id = self.next;
+            self.next = if id == PeerId::MAX { 1 } else { id + 1 };
+            if self.taken.insert(id) {
+                let resume_token = OsRng.next_u64();
+                self.resume_tokens.insert(id, resume_token);
+                return Some((id, resume_token));
+            }
+        }
+        None
+    }
+
+    /// Takes a specific id back if it's free and the token is the one handed out with it
+    fn take(&mut self, id: PeerId, resume_token: u64) -> bool {
+        id != 0 && self.resume_tokens.get(&id) == Some(&resume_token) && self.taken.insert(id)
+    }
+
+    /// Returns the token handed out with an id
+    fn resume_token(&self, id: PeerId) -> u64 {
+        self.resume_tokens.get(&id).copied().unwrap_or_default()
+    }
+
+    fn release(&mut self, id: PeerId) {
+        self.taken.remove(&id);
+    }
+}
+
 pub struct SignalingServer {
     username: String,
-    listener: TcpListener,
-    cipher: Arc<AES>,
-    streams: Arc<Mutex<HashMap<u8, TcpStream>>>,
-    audio_peers: Arc<Mutex<HashMap<u8, AudioPeer>>>,
-    index_counter: Arc<AtomicU8>,
+    /// A listener for each address family, with the address advertised for it
+    listeners: Vec<(TcpListener, String)>,
+    /// The rooms by name, the server talks in the hosted room
+    rooms: HashMap<String, Arc<Room>>,
+    audio_peers: Arc<Mutex<HashMap<PeerId, AudioPeer>>>,
+    playback: Arc<Mutex<DeviceSelection>>,
+    ids: Arc<Mutex<PeerIds>>,
+    /// Most peers in each room, the server included in the hosted room, 0 for no limit
+    max_peers: u16,
+    relay: Arc<Relay>,
+    heartbeat_interval: Duration,
+    heartbeat_timeout: Duration,
+    invites: Arc<Invites>,
 }
 impl SignalingServer {
-    pub fn new(username: String) -> Self {
-        let bind = signaling::get_address_ipv6();
-        let listener = TcpListener::bind(bind).unwrap();
-
-        let cipher = Arc::new(AES::new(None).unwrap());
-        SignalingServer {
-            username,
-            listener,
-            cipher,
-            streams: Arc::new(Mutex::new(HashMap::new())),
-            audio_peers: Arc::new(Mutex::new(HashMap::new())),
-            index_counter: Arc::new(AtomicU8::new(1)),
+    /// Creates a server and binds its listeners
+    /// # Arguments
+    /// * `username` - The username of the server in its room, None for a server that only does
+    ///   signaling and isn't in any room
+    /// * `listen` - A comma separated list of addresses to listen on, None to listen on the
+    ///   address of each family found with STUN
+    /// * `public` - A comma separated list of ips or ip:ports to advertise instead of the
+    ///   addresses found, one per family, for servers behind a NAT or a port forward
+    pub fn new(username: Option<String>, listen: Option<&str>, public: Option<&str>) -> Result<Self, std::io::Error> {
+        if username.as_ref().is_some_and(|username| username.len() > MAX_USERNAME_LEN) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("The username is longer than {} bytes", MAX_USERNAME_LEN),
+            ));
         }
+        let public = match public {
+            Some(public) => Self::parse_public(public)?,
+            None => Vec::new(),
+        };
+        let mut listeners: Vec<(TcpListener, String)> = match listen {
+            Some(listen) => signaling::split_candidates(listen)
+                .iter()
+                .map(|address| Self::bind_fixed(address))
+                .collect::<Result<_, _>>()?,
+            None => signaling::get_candidates()
+                .iter()
+                .filter_map(|candidate| Self::bind(candidate))
+                .collect(),
+        };
+        if listeners.is_empty() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::AddrNotAvailable,
+                "Couldn't listen on any address family",
+            ));
+        }
+        for (listener, advertised) in listeners.iter_mut() {
+            let local = match listener.local_addr() {
+                Ok(local) => local,
+                Err(_) => continue,
+            };
+            //The port is the one we listen on unless the public address names another
+            if let Some((ip, port)) = public.iter().find(|(ip, _)| ip.is_ipv6() == local.is_ipv6()) {
+                *advertised = SocketAddr::new(*ip, port.unwrap_or(local.port())).to_string();
+            }
+        }
+
+        let audio_peers = Arc::new(Mutex::new(HashMap::new()));
+        let relay = Arc::new(Relay::new(&listeners));
+        let hosted = Room::new(HOSTED_ROOM, None, audio_peers.clone(), relay.clone(), username.is_some()).unwrap();
+        let mut rooms = HashMap::new();
+        rooms.insert(HOSTED_ROOM.to_string(), Arc::new(hosted));
+        Ok(SignalingServer {
+            username: username.unwrap_or_default(),
+            listeners,
+            rooms,
+            audio_peers,
+            playback: Arc::new(Mutex::new(DeviceSelection::new("_", 2, 48_000))),
+            ids: Arc::new(Mutex::new(PeerIds {
+                next: 1,
+                taken: HashSet::new(),
+                resume_tokens: HashMap::new(),
+            })),
+            max_peers: 0,
+            relay,
+            heartbeat_interval: DEFAULT_HEARTBEAT_INTERVAL,
+            heartbeat_timeout: DEFAULT_HEARTBEAT_TIMEOUT,
+            invites: Arc::new(Invites::new()),
+        })
+    }
+
+    /// Adds a room the server doesn't talk in, its peers only mesh with each other
+    /// # Arguments
+    /// * `name` - The name clients join the room by
+    /// * `key` - The room key, a new key is generated if None
+    /// # Returns
+    /// * The room key
+    pub fn add_room(&mut self, name: &str, key: Option<&str>) -> Result<String, std::io::Error> {
+        let invalid = |reason: String| std::io::Error::new(std::io::ErrorKind::InvalidInput, reason);
+        if name == HOSTED_ROOM || name.len() > MAX_ROOM_NAME {
+            return Err(invalid(format!("Invalid room name \"{}\"", name)));
+        }
+        if self.rooms.contains_key(name) {
+            return Err(invalid(format!("Room \"{}\" is defined twice", name)));
+        }
+        let room = Room::new(name, key, Arc::new(Mutex::new(HashMap::new())), self.relay.clone(), false)
+            .map_err(|e| invalid(format!("Invalid key for room \"{}\": {}", name, e)))?;
+        let key = room.cipher.get_key();
+        self.rooms.insert(name.to_string(), Arc::new(room));
+        Ok(key)
+    }
+
+    /// Makes the server forward the voice of a room, each client of the room sends its voice once
+    /// to the server instead of once to every peer. The server can't forward in its own room.
+    /// # Arguments
+    /// * `name` - The name of the room, empty for the default room of a headless server
+    pub fn set_forwarding(&mut self, name: &str) -> Result<(), std::io::Error> {
+        let invalid = |reason: String| std::io::Error::new(std::io::ErrorKind::InvalidInput, reason);
+        if !self.relay.is_enabled() {
+            return Err(invalid("Forwarding rooms need the relay".to_string()));
+        }
+        let room = self
+            .rooms
+            .get_mut(name)
+            .ok_or_else(|| invalid(format!("No room \"{}\"", name)))?;
+        let room = Arc::get_mut(room).unwrap();
+        if room.hosted {
+            return Err(invalid("The server can't forward in its own room".to_string()));
+        }
+        room.forwarding = true;
+        Ok(())
+    }
+
+    /// Limits how many clients of each forwarding room are forwarded at the same time
+    /// # Arguments
+    /// * `max_speakers` - Most senders at the same time, 0 for no limit
+    pub fn set_max_speakers(&self, max_speakers: u16) {
+        self.relay.set_max_speakers(max_speakers);
     }
+
+    /// Returns the name and key of every room added with add_room
+    pub fn get_rooms(&self) -> Vec<(String, String)> {
+        let mut rooms: Vec<(String, String)> = self
+            .rooms
+            .values()
+            .filter(|room| room.name != HOSTED_ROOM)
+            .map(|room| (room.name.clone(), room.cipher.get_key()))
+            .collect();
+        rooms.sort();
+        rooms
+    }
+
+    /// Returns the link that joins a room, with the server addresses and the room key
+    /// # Arguments
+    /// * `room` - The name of the room, empty for the room of the server
+    /// * `token` - An invite token to put in the link
+    pub fn get_invite_link(&self, room: &str, token: Option<String>) -> Option<String> {
+        let key = self.rooms.get(room)?.cipher.get_key();
+        let link = InviteLink {
+            addresses: signaling::join_candidates(&self.get_listen_addresses()),
+            room: room.to_string(),
+            key,
+            token,
+        };
+        Some(link.to_string())
+    }
+
+    /// Hands out an invite link with a token the server checks when a client joins with it
+    /// # Arguments
+    /// * `room` - The name of the room, empty for the room of the server
+    /// * `lifetime` - How long the link is valid, None for no limit
+    /// * `single_use` - True if only one client may join with the link
+    /// # Returns
+    /// * The link and when it expires
+    pub fn create_invite(
+        &self,
+        room: &str,
+        lifetime: Option<Duration>,
+        single_use: bool,
+    ) -> Result<(String, Option<SystemTime>), std::io::Error> {
+        if !self.rooms.contains_key(room) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("No room \"{}\"", room),
+            ));
+        }
+        let (token, expires) = self.invites.issue(room, lifetime, single_use);
+        Ok((self.get_invite_link(room, Some(token)).unwrap(), expires))
+    }
+
+    /// Turns away the clients that don't join with an invite link handed out by create_invite
+    pub fn set_invite_required(&self, required: bool) {
+        self.invites.set_required(required);
+    }
+
+    /// Replaces the random key invite tokens are signed with, the tokens then stay valid after a restart
+    /// # Arguments
+    /// * `secret` - A key in the format of the room keys
+    pub fn set_invite_secret(&self, secret: &str) -> Result<(), std::io::Error> {
+        self.invites.set_secret(secret).map_err(|e| {
+            std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("Invalid invite secret: {}", e))
+        })
+    }
+
+    /// Revokes an invite token, the clients that already joined with it stay but can't reconnect
+    /// # Arguments
+    /// * `invite` - The invite link or its token
+    pub fn revoke_invite(&self, invite: &str) -> Result<(), std::io::Error> {
+        let token = match InviteLink::parse(invite) {
+            Ok(link) => link.token.ok_or_else(|| {
+                std::io::Error::new(std::io::ErrorKind::InvalidInput, "The link has no invite token")
+            })?,
+            Err(_) => invite.to_string(),
+        };
+        self.invites.revoke(&token).map_err(|_| {
+            std::io::Error::new(std::io::ErrorKind::NotFound, "The server didn't hand out this invite")
+        })
+    }
+
+    /// Revokes every invite token handed out so far, the signing key is replaced by a random key
+    pub fn revoke_all_invites(&self) {
+        self.invites.revoke_all();
+    }
+
+    fn hosted_room(&self) -> &Room {
+        &self.rooms[HOSTED_ROOM]
+    }
+
+    /// Listens on the candidate, or on any interface of the same family when the
+    /// candidate is a public address that isn't configured on this machine
+    /// # Returns
+    /// * The listener and the address to advertise for it
+    fn bind(candidate: &str) -> Option<(TcpListener, String)> {
+        let addr: SocketAddr = candidate.parse().ok()?;
+        if let Ok(listener) = signaling::bind_tcp(addr) {
+            return Some((listener, candidate.to_string()));
+        }
+        let unspecified: IpAddr = match addr {
+            SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+            SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+        };
+        let listener = signaling::bind_tcp(SocketAddr::new(unspecified, addr.port()))
+            .or_else(|_| signaling::bind_tcp(SocketAddr::new(unspecified, 0)))
+            .map_err(|e| error!("Couldn't listen on {}: {}", candidate, e))
+            .ok()?;
+        let port = listener.local_addr().ok()?.port();
+        Some((listener, SocketAddr::new(addr.ip(), port).to_string()))
+    }
+
+    /// Reads the public addresses, each one is an ip or an ip:port
+    fn parse_public(public: &str) -> Result<Vec<(IpAddr, Option<u16>)>, std::io::Error> {
+        signaling::split_candidates(public)
+            .iter()
+            .map(|address| {
+                if let Ok(addr) = address.parse::<SocketAddr>() {
+                    return Ok((addr.ip(), Some(addr.port())));
+                }
+                address.parse::<IpAddr>().map(|ip| (ip, None)).map_err(|_| {
+                    std::io::Error::new(
+                        std::io::ErrorKind::InvalidInput,
+                        format!("Invalid public address {}", address),
+                    )
+                })
+            })
+            .collect()
+    }
+
+    /// Listens on a fixed address. A client can't connect to an unspecified address,
+    /// the address of the same family found with STUN is advertised instead.
+    /// # Returns
+    /// * The listener and the address to advertise for it
+    /// # Errors
+    /// * The address is invalid or can't be bound, the server doesn't start without it
+    fn bind_fixed(address: &str) -> Result<(TcpListener, String), std::io::Error> {
+        let addr: SocketAddr = address.parse().map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("Invalid listen address {}: {}", address, e),
+            )
+        })?;
+        let listener = signaling::bind_tcp(addr)
+            .map_err(|e| std::io::Error::new(e.kind(), format!("Couldn't listen on {}: {}", address, e)))?;
+        let local = listener.local_addr()?;
+        if !local.ip().is_unspecified() {
+            return Ok((listener, local.to_string()));
+        }
+        let advertised = if local.is_ipv6() { signaling::get_address_ipv6() } else { signaling::get_address_ipv4() };
+        let ip = match advertised.ok().and_then(|advertised| advertised.parse::<SocketAddr>().ok()) {
+            Some(advertised) => advertised.ip(),
+            None => local.ip(),
+        };
+        Ok((listener, SocketAddr::new(ip, local.port()).to_string()))
+    }
+
+    /// Returns the preferred address clients should connect to
     pub fn get_listen_address(&self) -> String {
-        self.listener.local_addr().unwrap().to_string()
+        self.listeners[0].1.clone()
     }
+
+    /// Turns the relay off, pairs of peers that can't punch through are then left without audio
+    pub fn set_relay_enabled(&self, enabled: bool) {
+        self.relay.set_enabled(enabled);
+    }
+
+    /// Changes the bandwidth each relayed peer may send
+    /// # Arguments
+    /// * `kbps` - kbit/s, 0 for no limit
+    pub fn set_relay_bandwidth(&self, kbps: u32) {
+        self.relay.set_bandwidth(kbps);
+    }
+
+    /// Changes how often the clients are pinged and how long a silent client is kept
+    /// # Arguments
+    /// * `interval` - Time between two heartbeats, zero disables the heartbeats and the eviction
+    /// * `timeout` - Time without any message before a client is evicted, longer than the interval
+    pub fn set_heartbeat(&mut self, interval: Duration, timeout: Duration) {
+        self.heartbeat_interval = interval;
+        self.heartbeat_timeout = timeout;
+    }
+
+    /// Limits the number of peers in each room, the clients that join a full room are turned away
+    /// # Arguments
+    /// * `max_peers` - Most peers in a room, the server included in the hosted room, 0 for no limit
+    pub fn set_max_peers(&mut self, max_peers: u16) {
+        self.max_peers = max_peers;
+    }
+
+    /// Tells every client of the hosted room our mute and deafen state
+    pub fn send_presence(&self) {
+        self.hosted_room().broadcast_presence(0, roster::get_self_flags());
+    }
+
+    /// Returns the address of every listener, ipv6 first
+    pub fn get_listen_addresses(&self) -> Vec<String> {
+        self.listeners.iter().map(|(_, address)| address.clone()).collect()
+    }
+
+    /// Returns the key of the room clients join without naming one
     pub fn get_cipher_key(&self) -> String {
-        self.cipher.get_key().clone()
+        self.hosted_room().cipher.get_key()
     }
-    pub fn run(&self, playback_name: String) {
-        let listener_tryclone = self.listener.try_clone();
-        if listener_tryclone.is_err() {
-            panic!("Failed to clone listener");
+
+    pub fn run(&self, playback: DeviceSelection) {
+        *self.playback.lock().unwrap() = playback;
+        let playback = self.playback.clone();
+        self.relay.start();
+        //Every listener feeds the same accept loop
+        let (accept_tx, accept_rx) = flume::unbounded();
+        for (listener, address) in self.listeners.iter() {
+            let listener_tryclone = listener.try_clone();
+            if listener_tryclone.is_err() {
+                panic!("Failed to clone listener");
+            }
+            let listener = listener_tryclone.unwrap();
+            let accept_tx = accept_tx.clone();
+            spawn_thread!(format!("server tcp listener {address}"), move || loop {
+                if accept_tx.send(listener.accept()).is_err() {
+                    break;
+                }
+            });
         }
-        let listener = listener_tryclone.unwrap();
+        let rooms = Arc::new(self.rooms.clone());
         let audio_peers = self.audio_peers.clone();
-        let streams = self.streams.clone();
-        let aes = self.cipher.clone();
         let my_username = self.username.clone();
-        let index_counter = self.index_counter.clone();
+        let ids = self.ids.clone();
+        let invites = self.invites.clone();
+        let max_peers = self.max_peers;
+        let heartbeat_timeout = if self.heartbeat_interval.is_zero() {
+            None
+        } else {
+            let rooms = rooms.clone();
+            let (interval, timeout) = (self.heartbeat_interval, self.heartbeat_timeout);
+            spawn_thread!("server heartbeat", move || loop {
+                thread::sleep(interval);
+                rooms.values().for_each(|room| room.send_heartbeats(timeout));
+            });
+            Some(self.heartbeat_timeout)
+        };
         spawn_thread!("server tpc listener", move || {
-            let audio_peers = audio_peers.clone();
-            let streams = streams.clone();
-            let my_username = my_username.clone();
             println!("{{ \"event_code\": 1 }}");
             loop {
-                let audio_peers = audio_peers.clone();
-                let streams = streams.clone();
-                let my_username = my_username.clone();
-
-                let try_accept = listener.accept();
+                let try_accept = match accept_rx.recv() {
+                    Ok(try_accept) => try_accept,
+                    Err(_) => break,
+                };
                 if try_accept.is_err() {
                     error!("{:?}", try_accept.err());
                     continue;
                 }
-                let (mut stream, addr) = try_accept.unwrap();
+                let (stream, addr) = try_accept.unwrap();
                 debug!("New connection from {}", addr);
 
-                let id = index_counter.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
-                let welcome_prim = vec![0, id];
-                let welcome_msg = Bytes::from(welcome_prim);
-                let encrypted_msg = aes.encrypt(welcome_msg).unwrap();
-                let _ = stream.write_all(&encrypted_msg);
-                streams
-                    .lock()
-                    .unwrap()
-                    .insert(id, stream.try_clone().unwrap());
-
-                let aes_clone = aes.clone();
-                let playback_name = playback_name.clone();
-                spawn_thread!(format!("server tcp stream signaling n_{id}"), move || {
-                    let recv_buffer = &mut [0u8; 1024];
-                    let streams = streams.clone();
+                let rooms = rooms.clone();
+                let audio_peers = audio_peers.clone();
+                let playback = playback.clone();
+                let my_username = my_username.clone();
+                let ids = ids.clone();
+                let invites = invites.clone();
+                //The room is read on the client's own thread, a slow client can't hold up the others
+                spawn_thread!(format!("server tcp stream signaling {addr}"), move || {
+                    let mut stream = stream;
+                    let (room, invite) = match Self::read_hello(&mut stream, &rooms) {
+                        Ok(hello) => hello,
+                        Err(e) => {
+                            debug!("Rejected {}: {}", addr, e);
+                            return;
+                        }
+                    };
+                    //A read that times out evicts the client, a dead client can't block the other writers
+                    if let Err(e) = stream
+                        .set_read_timeout(heartbeat_timeout)
+                        .and_then(|_| stream.set_write_timeout(heartbeat_timeout))
+                    {
+                        error!("Failed to set the timeouts of {}: {}", addr, e);
+                    }
+                    let presented = invite.as_ref().map(|(token, session)| (token.as_str(), *session));
+                    if let Err(reason) = invites.check(&room.name, presented) {
+                        Self::refuse(&room, &mut stream, reason, addr);
+                        return;
+                    }
+                    let mut id = match Self::join(&room, &mut stream, &ids, max_peers, &my_username, addr) {
+                        Some(id) => id,
+                        None => return,
+                    };
+                    let aes_clone = room.cipher.clone();
+                    let streams = room.streams.clone();
+                    let usernames = room.usernames.clone();
+                    let punch = room.punch.clone();
                     loop {
-                        let audio_peers = audio_peers.clone();
-                        match stream.read(recv_buffer.as_mut()) {
-                            Ok(recv_len) => {
-                                if recv_len == 0 {
-                                    debug!("Connection closed");
-                                    streams.lock().unwrap().remove(&id);
-                                    audio_peers.lock().unwrap().remove(&id);
-                                    println!("{{ \"event_code\": 3, \"id\": {} }}", id);
-                                    streams.lock().unwrap().iter().for_each(|(sid, stream)| {
-                                        let mut reply = BytesMut::with_capacity(1024);
-                                        reply.put_u8(4);
-                                        reply.put_u8(0);
-                                        reply.put_u8(*sid);
-                                        reply.put_u8(id);
-                                        let encrypted_reply = aes_clone.encrypt(reply.freeze()).unwrap();
-                                        let _ = stream.try_clone().as_mut().unwrap().write_all(&encrypted_reply);
-                                    });
-
-                                    return;
-                                }
-                                let try_decrypt =
-                                    aes_clone.decrypt_vec(recv_buffer[..recv_len].to_vec());
+                        match signaling::read_frame(&mut stream) {
+                            Ok(frame) => {
+                                let try_decrypt = aes_clone.decrypt_vec(frame.clone());
                                 if try_decrypt.is_err() {
                                     error!(
                                         "Failed to decrypt message: {}",
@@ -122,23 +511,33 @@ impl SignalingServer {
 
                                 let decrypted = try_decrypt.unwrap();
                                 debug!("Received message: {:?}", decrypted);
-                                let to_id = decrypted[2];
+                                let (opcode, from_id, to_id) = match signaling::parse_header(&decrypted) {
+                                    Some(header) => header,
+                                    None => continue,
+                                };
                                 if to_id == 0 {
-                                    let opcode = decrypted[0];
-                                    let from_id = decrypted[1];
 
                                     match opcode {
                                         1 => {
-                                            let payload = decrypted[3..].to_vec();
-                                            let ip_len = 1 + payload[0] as usize;
-                                            let ip_candidate = std::str::from_utf8(&payload[1..ip_len]).unwrap();
-                                            debug!("Received ip candidate: {}", ip_candidate);
-                                            let username = std::str::from_utf8(&payload[ip_len..]).unwrap();
-                                            let my_addr_candidate = signaling::get_address_ipv6();
-                                            let audio_peer = AudioPeer::new(
-                                                my_addr_candidate.clone(),
-                                                aes_clone.get_key(),
-                                            );
+                                            if !room.hosted {
+                                                error!("Received an announce in room \"{}\" the server isn't in", room.name);
+                                                continue;
+                                            }
+                                            let payload = &decrypted[HEADER_LEN..];
+                                            let (remote_connection, ip_candidates, ip_len) = match signaling::read_announce(payload) {
+                                                Some(announce) => announce,
+                                                None => continue,
+                                            };
+                                            debug!("Received ip candidates: {}", ice::candidates_to_text(&ip_candidates));
+                                            let flags = match payload.get(ip_len) {
+                                                Some(flags) => *flags,
+                                                None => continue,
+                                            };
+                                            let username = &signaling::limit_username(&String::from_utf8_lossy(&payload[ip_len + 1..]));
+                                            usernames.lock().unwrap().insert(from_id, username.to_string());
+                                            let audio_peer = AudioPeer::new(from_id, aes_clone.get_key());
+                                            let my_addr_candidate = ice::candidates_to_text(audio_peer.get_candidates());
+                                            let my_connection = audio_peer.get_connection_id();
                                             audio_peers
                                                 .lock()
                                                 .unwrap()
@@ -146,20 +545,23 @@ impl SignalingServer {
 
                                             let unlocked_peers = audio_peers.lock().unwrap();
                                             let audio_peer = unlocked_peers.get(&from_id).unwrap();
-                                            println!("{{ \"event_code\": 2, \"id\": {}, \"username\": \"{}\" }}", from_id, username);
-                                            audio_peer.connect(ip_candidate, &playback_name);
+                                            roster::join(from_id, username, flags);
+                                            let playback = playback.lock().unwrap().clone();
+                                            audio_peer.connect(&ip_candidates, remote_connection, &playback);
 
                                             let mut reply = BytesMut::with_capacity(1024);
-                                            reply.put_u8(2);
-                                            reply.put_u8(0);
-                                            reply.put_u8(from_id);
-                                            reply.put_u8(my_addr_candidate.len() as u8);
+                                            signaling::put_header(&mut reply, 2, 0, from_id);
+                                            reply.put_u32(my_connection);
+                                            reply.put_u16(my_addr_candidate.len() as u16);
                                             reply.put(my_addr_candidate.as_bytes());
+                                            reply.put_u8(roster::get_self_flags());
                                             reply.put(my_username.as_bytes());
 
-                                            let encrypted =
-                                                aes_clone.encrypt(reply.freeze()).unwrap();
-                                            let _ = stream.write_all(&encrypted);
+                                            //Every write to a client goes through the lock of the room streams
+                                            if let Some(stream) = streams.lock().unwrap().get_mut(&from_id) {
+                                                let _ = signaling::write_message(stream, &aes_clone, reply);
+                                            }
+                                            punch.schedule(0, from_id, 0);
                                         }
                                         2 => {
                                             error!("Received unexpected opcode 2");
@@ -168,6 +570,57 @@ impl SignalingServer {
                                         3 => {
                                             todo!("Change bitrate or let AudioPeer handle it");
                                         }
+                                        6 => {
+                                            if decrypted.len() < HEADER_LEN + 3 {
+                                                continue;
+                                            }
+                                            punch.on_failed(from_id, signaling::read_id(&decrypted, HEADER_LEN), decrypted[HEADER_LEN + 2]);
+                                        }
+                                        8 => {
+                                            if decrypted.len() < HEADER_LEN + 10 {
+                                                continue;
+                                            }
+                                            let previous_id = signaling::read_id(&decrypted, HEADER_LEN);
+                                            let resume_token = u64::from_be_bytes(decrypted[HEADER_LEN + 2..HEADER_LEN + 10].try_into().unwrap());
+                                            let mut unlocked_streams = streams.lock().unwrap();
+                                            let mut unlocked_usernames = usernames.lock().unwrap();
+                                            //The id is free once its connection is gone, in any room, and only its
+                                            //previous client knows its token
+                                            let mut unlocked_ids = ids.lock().unwrap();
+                                            if unlocked_ids.take(previous_id, resume_token) {
+                                                unlocked_ids.release(id);
+                                                if let Some(moved) = unlocked_streams.remove(&id) {
+                                                    unlocked_streams.insert(previous_id, moved);
+                                                }
+                                                if let Some(username) = unlocked_usernames.remove(&id) {
+                                                    unlocked_usernames.insert(previous_id, username);
+                                                }
+                                                punch.remove_peer(id);
+                                                debug!("Peer {} resumed id {}", id, previous_id);
+                                                id = previous_id;
+                                            }
+                                            let resume_token = unlocked_ids.resume_token(id);
+                                            drop(unlocked_ids);
+                                            let welcome_msg = room.welcome(id, resume_token, &unlocked_streams, &unlocked_usernames, &my_username);
+                                            drop(unlocked_usernames);
+                                            if let Some(stream) = unlocked_streams.get_mut(&id) {
+                                                let _ = signaling::write_message(stream, &aes_clone, welcome_msg);
+                                            }
+                                            drop(unlocked_streams);
+                                        }
+                                        9 => {
+                                            //Heartbeat answer, the read itself keeps the client alive
+                                        }
+                                        12 => {
+                                            if decrypted.len() < HEADER_LEN + 1 {
+                                                continue;
+                                            }
+                                            let flags = decrypted[HEADER_LEN];
+                                            if room.hosted {
+                                                roster::set_presence(from_id, flags);
+                                            }
+                                            room.broadcast_presence(from_id, flags);
+                                        }
                                         _ => {
                                             error!("Unknown opcode {}", opcode);
                                             continue;
@@ -181,23 +634,35 @@ impl SignalingServer {
                                         continue;
                                     }
                                     let stream = stream.unwrap();
-                                    let _ = stream.write_all(&recv_buffer[..recv_len]);
+                                    let _ = signaling::write_frame(stream, &frame);
+                                    drop(streams);
+                                    //Announces and acknowledges carry the username, the roster of the next client lists it
+                                    if matches!(opcode, 1 | 2) {
+                                        if let Some(username) = Self::read_username(&decrypted[HEADER_LEN..]) {
+                                            usernames.lock().unwrap().entry(from_id).or_insert(username);
+                                        }
+                                        //The sender picked this connection id for the packets of the receiver
+                                        if let (true, Some(connection)) = (room.forwarding, decrypted.get(HEADER_LEN..HEADER_LEN + 4)) {
+                                            let connection = u32::from_be_bytes([connection[0], connection[1], connection[2], connection[3]]);
+                                            room.set_connection(from_id, to_id, connection);
+                                        }
+                                    }
+                                    //Both peers know each other's candidates once the acknowledge is through,
+                                    //the peers of a forwarding room talk through the server right away
+                                    if opcode == 2 && !room.forwarding {
+                                        punch.schedule(from_id, to_id, 0);
+                                    }
                                 }
                             }
                             Err(e) => {
-                                debug!("Connection closed");
-                                streams.lock().unwrap().remove(&id);
-                                audio_peers.lock().unwrap().remove(&id);
-                                println!("{{ \"event_code\": 3, \"id\": {} }}", id);
-                                streams.lock().unwrap().iter().for_each(|(sid, stream)| {
-                                    let mut reply = BytesMut::with_capacity(1024);
-                                    reply.put_u8(4);
-                                    reply.put_u8(0);
-                                    reply.put_u8(*sid);
-                                    reply.put_u8(id);
-                                    let encrypted_reply = aes_clone.encrypt(reply.freeze()).unwrap();
-                                    let _ = stream.try_clone().as_mut().unwrap().write_all(&encrypted_reply);
-                                });
+                                if e.kind() == std::io::ErrorKind::UnexpectedEof {
+                                    debug!("Connection closed");
+                                } else if matches!(e.kind(), std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut) {
+                                    info!("Evicting peer {}, no heartbeat for {:?}", id, heartbeat_timeout);
+                                } else {
+                                    debug!("Connection closed: {}", e);
+                                }
+                                Self::leave(&room, id, &ids, &audio_peers);
                                 return;
                             }
                         }
@@ -207,6 +672,117 @@ impl SignalingServer {
         });
     }
 
+    /// Reads the name of the room a new connection joins, sent in clear as `<u8 name_len><name>`
+    /// because the room key depends on it. An invite follows the name as
+    /// `<u8 token_len><token><u64 session>` when the top bit of name_len is set.
+    /// # Returns
+    /// * The room and the invite token with the session of the client, an error if the room doesn't exist
+    fn read_hello(
+        stream: &mut TcpStream,
+        rooms: &HashMap<String, Arc<Room>>,
+    ) -> Result<(Arc<Room>, Option<PresentedInvite>), std::io::Error> {
+        stream.set_read_timeout(Some(HELLO_TIMEOUT))?;
+        let mut name_len = [0u8; 1];
+        stream.read_exact(&mut name_len)?;
+        let mut name = vec![0u8; (name_len[0] & !INVITE_FLAG) as usize];
+        stream.read_exact(&mut name)?;
+        let name = String::from_utf8_lossy(&name).to_string();
+        let invite = if name_len[0] & INVITE_FLAG != 0 {
+            let mut token_len = [0u8; 1];
+            stream.read_exact(&mut token_len)?;
+            let mut token = vec![0u8; token_len[0] as usize];
+            stream.read_exact(&mut token)?;
+            let mut session = [0u8; 8];
+            stream.read_exact(&mut session)?;
+            Some((String::from_utf8_lossy(&token).to_string(), u64::from_be_bytes(session)))
+        } else {
+            None
+        };
+        let room = rooms.get(&name).cloned().ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::NotFound, format!("No room \"{}\"", name))
+        })?;
+        Ok((room, invite))
+    }
+
+    /// Turns away a client whose invite isn't valid
+    /// # Arguments
+    /// * `room` - The room the client tried to join
+    /// * `stream` - The stream of the client
+    /// * `reason` - Why the invite isn't valid
+    /// * `addr` - The address of the client
+    fn refuse(room: &Room, stream: &mut TcpStream, reason: Refusal, addr: SocketAddr) {
+        println!(
+            "{{ \"event_code\": 23, \"reason\": \"{}\", \"address\": \"{}\" }}",
+            reason.to_text(),
+            addr
+        );
+        let mut refused_msg = BytesMut::with_capacity(2);
+        refused_msg.put_u8(11);
+        refused_msg.put_u8(reason as u8);
+        let _ = signaling::write_message(stream, &room.cipher, refused_msg);
+    }
+
+    /// Gives a new client an id in its room, or turns it away if the room is full
+    /// # Arguments
+    /// * `room` - The room the client joins
+    /// * `stream` - The stream of the client
+    /// * `ids` - The ids in use
+    /// * `max_peers` - Most peers in a room, 0 for no limit
+    /// * `my_username` - The username of the server
+    /// * `addr` - The address of the client
+    /// # Returns
+    /// * The id of the client, None if it was turned away
+    fn join(
+        room: &Room,
+        stream: &mut TcpStream,
+        ids: &Mutex<PeerIds>,
+        max_peers: u16,
+        my_username: &str,
+        addr: SocketAddr,
+    ) -> Option<PeerId> {
+        let mut unlocked_streams = room.streams.lock().unwrap();
+        let full = room.is_full(&unlocked_streams, max_peers);
+        let allocated = if full { None } else { ids.lock().unwrap().allocate() };
+        let (id, resume_token) = match allocated {
+            Some(allocated) => allocated,
+            None => {
+                drop(unlocked_streams);
+                let max_peers = if full { max_peers } else { PeerId::MAX };
+                println!(
+                    "{{ \"event_code\": 18, \"max_peers\": {}, \"address\": \"{}\" }}",
+                    max_peers, addr
+                );
+                let mut full_msg = BytesMut::with_capacity(3);
+                full_msg.put_u8(10);
+                full_msg.put_u16(max_peers);
+                let _ = signaling::write_message(stream, &room.cipher, full_msg);
+                return None;
+            }
+        };
+        let welcome_msg = room.welcome(id, resume_token, &unlocked_streams, &room.usernames.lock().unwrap(), my_username);
+        let _ = signaling::write_message(stream, &room.cipher, welcome_msg);
+        unlocked_streams.insert(id, stream.try_clone().unwrap());
+        Some(id)
+    }
+
+    /// Reads the username of an announce or acknowledge payload,
+    /// `<u32 connection><u16 ip_len><ip><u8 presence_flags><username>`
+    fn read_username(payload: &[u8]) -> Option<String> {
+        let ip_len = 6 + u16::from_be_bytes([*payload.get(4)?, *payload.get(5)?]) as usize;
+        let username = payload.get(ip_len + 1..)?;
+        Some(signaling::limit_username(&String::from_utf8_lossy(username)))
+    }
+
+    /// Forgets a client that disconnected or was evicted
+    fn leave(room: &Room, id: PeerId, ids: &Mutex<PeerIds>, audio_peers: &Mutex<HashMap<PeerId, AudioPeer>>) {
+        room.remove(id);
+        ids.lock().unwrap().release(id);
+        if room.hosted {
+            audio_peers.lock().unwrap().remove(&id);
+            roster::leave(id);
+        }
+    }
+
     pub fn send_opus(&self, opus_packet: Bytes) {
         let peers = self.audio_peers.lock().unwrap();
         for peer in peers.values() {
@@ -214,21 +790,13 @@ impl SignalingServer {
         }
     }
 
-    pub fn change_playback(&self, device_name: &String, channels: u32, sample_rate: u32) {
-        let peers = self.audio_peers.lock().unwrap();
-        for peer in peers.values() {
-            peer.change_device(device_name, channels, sample_rate);
-        }
+    /// Returns the audio peers, the stdin commands change their volume and playback device
+    pub fn get_audio_peers(&self) -> Arc<Mutex<HashMap<PeerId, AudioPeer>>> {
+        self.audio_peers.clone()
     }
 
-    pub fn change_peer_volume(&self, peer_id: u8, volume: u8) {
-        let peers = self.audio_peers.lock().unwrap();
-        let peer = peers.get(&peer_id);
-        if peer.is_none() {
-            error!("Peer {} not found", peer_id);
-            return;
-        }
-        let peer = peer.unwrap();
-        peer.change_volume(volume);
+    /// Returns the playback device new peers are played on
+    pub fn get_playback(&self) -> Arc<Mutex<DeviceSelection>> {
+        self.playback.clone()
     }
 }
@@ -0,0 +1,189 @@
+// SPDX-FileCopyrightText: Copyright 2023 tSVoI
+// SPDX-License-Identifier: GPL-3.0-only
+
+use aead::rand_core::RngCore;
+use aead::OsRng;
+use bytes::{BufMut, Bytes, BytesMut};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::signaling::relay::Relay;
+use crate::signaling::PeerId;
+
+/// Connection id of the packets a client sends to its whole room
+pub const ROOM_CONNECTION: u32 = 0;
+/// A sender stops speaking when none of its voice arrives for this long
+const SPEAKING_HOLD: Duration = Duration::from_millis(300);
+
+/// A client of a forwarding room, identified by the token its packets start with
+struct Member {
+    owner: PeerId,
+    room: String,
+    /// Learned from the last packet of the owner
+    addr: Option<SocketAddr>,
+    /// Bytes the owner may still send in the current second
+    budget: u32,
+    budget_start: Instant,
+    /// When the owner last sent voice to the room
+    last_voice: Option<Instant>,
+    /// When the owner started speaking, earlier speakers keep their slot
+    speaking_since: Option<Instant>,
+}
+impl Member {
+    fn is_speaking(&self) -> bool {
+        self.last_voice.is_some_and(|last| last.elapsed() < SPEAKING_HOLD)
+    }
+}
+
+/// Forwards the voice of the clients of forwarding (SFU) rooms. Each client sends its voice once
+/// as `<u64 token><u32 0><encrypted voice>` and the server copies it to every other client of the
+/// room, with the connection id each of them picked for the sender. Packets with another
+/// connection id, the keepalives, only go to the client that picked it.
+pub struct Sfu {
+    members: Mutex<HashMap<u64, Member>>,
+    /// The connection id each receiver picked for each sender, keyed by (receiver, sender)
+    connections: Mutex<HashMap<(PeerId, PeerId), u32>>,
+    /// Most senders forwarded at the same time in a room, 0 for no limit
+    max_speakers: AtomicU16,
+}
+impl Sfu {
+    pub fn new() -> Self {
+        Sfu {
+            members: Mutex::new(HashMap::new()),
+            connections: Mutex::new(HashMap::new()),
+            max_speakers: AtomicU16::new(0),
+        }
+    }
+
+    /// Adds a client to a forwarding room, a client that was already in it gets a new token
+    /// # Arguments
+    /// * `room` - The name of the room
+    /// * `id` - The id of the client
+    /// * `budget` - Bytes the client may send each second
+    /// # Returns
+    /// * The token the client puts in front of its packets
+    pub fn join(&self, room: &str, id: PeerId, budget: u32) -> u64 {
+        let mut members = self.members.lock().unwrap();
+        members.retain(|_, member| member.owner != id);
+        let token = OsRng.next_u64();
+        members.insert(
+            token,
+            Member {
+                owner: id,
+                room: room.to_string(),
+                addr: None,
+                budget,
+                budget_start: Instant::now(),
+                last_voice: None,
+                speaking_since: None,
+            },
+        );
+        debug!("Peer {} forwards through room \"{}\"", id, room);
+        token
+    }
+
+    /// Records the connection id a client picked for the packets of another client,
+    /// read from the announces and acknowledges the server forwards
+    /// # Arguments
+    /// * `receiver` - The client that picked the connection id
+    /// * `sender` - The client that puts it in front of its packets
+    /// * `connection` - The connection id
+    pub fn set_connection(&self, receiver: PeerId, sender: PeerId, connection: u32) {
+        self.connections.lock().unwrap().insert((receiver, sender), connection);
+    }
+
+    /// Limits how many clients of a room are forwarded at the same time, the clients that start
+    /// speaking while every slot is taken are dropped until a slot frees
+    /// # Arguments
+    /// * `max_speakers` - Most senders at the same time, 0 for no limit
+    pub fn set_max_speakers(&self, max_speakers: u16) {
+        self.max_speakers.store(max_speakers, Ordering::Relaxed);
+    }
+
+    /// Learns the address of the sender and returns where its packet goes
+    /// # Arguments
+    /// * `token` - The token the packet starts with
+    /// * `source` - The address the packet came from
+    /// * `packet` - The packet without the token, `<u32 connection><encrypted packet>`
+    /// * `bandwidth` - kbit/s the sender may use, 0 for no limit
+    /// # Returns
+    /// * Each destination with its packet, None if the token isn't a client of a forwarding room
+    pub fn route(
+        &self,
+        token: u64,
+        source: SocketAddr,
+        packet: &[u8],
+        bandwidth: u32,
+    ) -> Option<Vec<(SocketAddr, Bytes)>> {
+        let mut members = self.members.lock().unwrap();
+        let member = members.get_mut(&token)?;
+        member.addr = Some(source);
+        //A bare token only refreshes the owner's address
+        if packet.len() < 4 {
+            return Some(Vec::new());
+        }
+        if !Relay::spend(&mut member.budget, &mut member.budget_start, packet.len() as u32, bandwidth) {
+            debug!("Peer {} is over the relay bandwidth, dropping a packet", member.owner);
+            return Some(Vec::new());
+        }
+        let (sender, room) = (member.owner, member.room.clone());
+        let connection = u32::from_be_bytes([packet[0], packet[1], packet[2], packet[3]]);
+        let connections = self.connections.lock().unwrap();
+        if connection != ROOM_CONNECTION {
+            let destination = members
+                .values()
+                .filter(|receiver| receiver.room == room && receiver.owner != sender)
+                .filter(|receiver| connections.get(&(receiver.owner, sender)) == Some(&connection))
+                .find_map(|receiver| receiver.addr);
+            return Some(destination.map(|addr| (addr, Bytes::copy_from_slice(packet))).into_iter().collect());
+        }
+        if !self.take_slot(&mut members, token) {
+            return Some(Vec::new());
+        }
+        let destinations = members
+            .values()
+            .filter(|receiver| receiver.room == room && receiver.owner != sender)
+            .filter_map(|receiver| {
+                let connection = connections.get(&(receiver.owner, sender))?;
+                let mut framed = BytesMut::with_capacity(packet.len());
+                framed.put_u32(*connection);
+                framed.put(&packet[4..]);
+                Some((receiver.addr?, framed.freeze()))
+            })
+            .collect();
+        Some(destinations)
+    }
+
+    /// Marks the sender as speaking and tells if its voice is forwarded
+    fn take_slot(&self, members: &mut HashMap<u64, Member>, token: u64) -> bool {
+        let now = Instant::now();
+        let member = members.get_mut(&token).unwrap();
+        if !member.is_speaking() {
+            member.speaking_since = Some(now);
+        }
+        member.last_voice = Some(now);
+        let max_speakers = self.max_speakers.load(Ordering::Relaxed);
+        if max_speakers == 0 {
+            return true;
+        }
+        let (room, since) = (member.room.clone(), member.speaking_since);
+        let earlier = members
+            .iter()
+            .filter(|(other, member)| **other != token && member.room == room && member.is_speaking())
+            .filter(|(_, member)| member.speaking_since < since)
+            .count();
+        earlier < max_speakers as usize
+    }
+
+    /// Forgets a client that left
+    pub fn release(&self, id: PeerId) {
+        self.members.lock().unwrap().retain(|_, member| member.owner != id);
+        self.connections
+            .lock()
+            .unwrap()
+            .retain(|(receiver, sender), _| *receiver != id && *sender != id);
+    }
+}